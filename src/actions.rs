@@ -0,0 +1,96 @@
+//! GApplication actions, every [`BackendServerCommand`] can also be activated with
+//! `gapplication action com.github.cr3eperall.dynisland <action> [parameter]`
+//! (the application id of a named instance ends with `.<instance>`).
+//!
+//! Actions can't return anything, so the responses of the list and cli commands are logged.
+
+use dynisland_core::{
+    abi::{glib, log},
+    graphics::activity_widget::boxed_activity_mode::ActivityMode,
+};
+use glib::Variant;
+use gtk::{gio::SimpleAction, prelude::*};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    app::{App, BackendServerCommand},
+    ipc::{self, protocol::RequestId, ACTION_REQUEST_ID},
+};
+
+type ActionHandler = fn(Option<&Variant>) -> Option<BackendServerCommand>;
+
+impl App {
+    pub(crate) fn register_actions(
+        &self,
+        server_send: UnboundedSender<(RequestId, BackendServerCommand)>,
+    ) {
+        let actions: [(&str, Option<glib::VariantType>, ActionHandler); 11] = [
+            ("reload", None, |_| Some(BackendServerCommand::ReloadConfig)),
+            ("rollback-config", None, |_| {
+                Some(BackendServerCommand::RollbackConfig)
+            }),
+            ("stop", None, |_| Some(BackendServerCommand::Stop)),
+            ("restart", None, |_| Some(BackendServerCommand::Restart)),
+            ("open-inspector", None, |_| {
+                Some(BackendServerCommand::OpenInspector)
+            }),
+            // ("activity@module", mode, duration), a duration of 0 uses the default one
+            (
+                "notify-activity",
+                Some(<(String, u8, u64)>::static_variant_type().into_owned()),
+                notify_activity,
+            ),
+            ("status", None, |_| Some(BackendServerCommand::Status)),
+            ("list-activities", None, |_| {
+                Some(BackendServerCommand::ListActivities)
+            }),
+            ("list-loaded-modules", None, |_| {
+                Some(BackendServerCommand::ListLoadedModules)
+            }),
+            // (module name, arguments)
+            (
+                "module-command",
+                Some(<(String, String)>::static_variant_type().into_owned()),
+                |param| {
+                    let (module_name, args) = param?.get::<(String, String)>()?;
+                    Some(BackendServerCommand::ModuleCliCommand(module_name, args))
+                },
+            ),
+            (
+                "layout-command",
+                Some(String::static_variant_type().into_owned()),
+                |param| {
+                    let args = param?.get::<String>()?;
+                    Some(BackendServerCommand::LayoutCliCommand(args))
+                },
+            ),
+        ];
+
+        for (name, parameter_type, handler) in actions {
+            let action = SimpleAction::new(name, parameter_type.as_deref());
+            let server_send = server_send.clone();
+            action.connect_activate(move |action, param| {
+                log::debug!("action {} activated with {param:?}", action.name());
+                let Some(command) = handler(param) else {
+                    log::error!("invalid parameter for action {}: {param:?}", action.name());
+                    return;
+                };
+                if let Err(err) = server_send.send((ACTION_REQUEST_ID, command)) {
+                    log::error!("failed to run action {}: {err}", action.name());
+                }
+            });
+            self.application.add_action(&action);
+        }
+    }
+}
+
+fn notify_activity(param: Option<&Variant>) -> Option<BackendServerCommand> {
+    let (activity_identifier, mode, duration) = param?.get::<(String, u8, u64)>()?;
+    let activity_id = ipc::parse_activity_identifier(&activity_identifier)?;
+    let mode = ActivityMode::try_from(mode).ok()?;
+    Some(BackendServerCommand::ActivityNotification(
+        activity_id,
+        mode,
+        (duration != 0).then_some(duration),
+    ))
+}
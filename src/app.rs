@@ -1,9 +1,11 @@
 use std::{
+    cell::{Cell, RefCell},
     collections::HashMap,
-    io::ErrorKind,
+    io,
     path::{Path, PathBuf},
     rc::Rc,
     thread,
+    time::Instant,
 };
 
 use abi_stable::{
@@ -14,7 +16,7 @@ use abi_stable::{
         RString,
     },
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use dynisland_core::{
     abi::{
         abi_stable, gdk, glib,
@@ -26,21 +28,43 @@ use dynisland_core::{
     ron,
 };
 use gtk::{prelude::*, CssProvider, Widget};
-use notify::{RecommendedWatcher, Watcher};
-use ron::{extensions::Extensions, ser::PrettyConfig};
+use nix::sys::signal::Signal;
+use ron::extensions::Extensions;
 use tokio::sync::{mpsc::unbounded_channel, Mutex};
 
 use crate::{
-    config::{self, Config, GeneralConfig},
-    ipc::open_socket,
+    config::{
+        self,
+        watcher::{ConfigWatcher, RecordingFs},
+        Config, GeneralConfig,
+    },
+    error_activity::ErrorActivity,
+    events::{self, Event, EventSender},
+    instance::{self, Instance},
+    ipc::{
+        dbus, open_socket,
+        protocol::{
+            ActivityInfo, ActivityMetadataInfo, ErrorKind, LayoutInfo, ModuleInfo, ReloadStatus,
+            RequestId, Response,
+        },
+        IpcServer, PendingRequests, INTERNAL_REQUEST_ID,
+    },
     layout_manager::{self, fallback_layout},
+    module_loading::ModuleOrigin,
+    readiness::{Readiness, Stage},
+    reload::{self, KnownGood, Rejected},
+    restart::{self, Notifications, Restore, SavedState},
+    systemd,
 };
 
 pub enum BackendServerCommand {
     ReloadConfig,
+    RollbackConfig,
     Stop,
+    Restart,
     OpenInspector,
     ActivityNotification(ActivityIdentifier, ActivityMode, Option<u64>),
+    Status,
     ListActivities,
     ListLoadedModules,
     ModuleCliCommand(String, String),
@@ -58,26 +82,70 @@ pub struct App {
     pub config: Config,
     pub css_provider: CssProvider,
     pub config_dir: PathBuf,
+    pub instance: Instance,
+    pub module_origins: HashMap<String, ModuleOrigin>,
+    /// Order in which the modules were initialized, they are shut down in reverse
+    pub module_order: Vec<String>,
+    pub event_send: EventSender,
+    /// Events generated before the IPC server was started, they are replayed to every subscriber
+    pub startup_events: Vec<Event>,
+    pub readiness: Readiness,
+    /// State saved by the daemon before it restarted, it's applied when the activities are registered again
+    pub restore_state: Option<SavedState>,
+    /// Notifications that could still be showing, they are saved on restart
+    pub notifications: Notifications,
+    /// Computed when the daemon starts, the config can't move it until the next start
+    pub runtime_dir: PathBuf,
+    /// Set when the daemon quits to exec itself again
+    pub restart_requested: Rc<Cell<bool>>,
+    pub started_at: Instant,
+    /// Name of the GSK renderer, it's known once the windows are realized
+    pub renderer: String,
+    pub last_reload: Option<ReloadStatus>,
+    /// Config files that were last applied without errors
+    pub known_good: KnownGood,
+    /// Compiled `dynisland.scss` in `css_provider`
+    pub stylesheet: String,
+    /// Files read to compile the stylesheet, `dynisland.scss` and the ones it imports
+    pub stylesheet_files: Vec<PathBuf>,
+    /// Content of `dynisland.ron` when the config in use was parsed, `None` if it doesn't exist
+    pub config_source: Option<String>,
+    /// Content of the files `stylesheet` was compiled from
+    pub stylesheet_sources: Vec<(PathBuf, Vec<u8>)>,
+    pub config_watcher: Option<ConfigWatcher>,
+    /// Created the first time the config has errors
+    pub error_activity: Option<ErrorActivity>,
 }
 
 impl App {
     pub fn run(mut self, config_dir: &Path) -> Result<()> {
         self.config = config::get_config(config_dir);
         self.config_dir = config_dir.to_path_buf();
-
-        let (server_send, server_recv) = unbounded_channel::<BackendServerCommand>();
-        let (server_response_send, server_response_recv) = unbounded_channel::<Option<String>>();
-        let runtime_path = self.config.get_runtime_dir();
+        self.application
+            .set_application_id(Some(&self.instance.application_id()));
+
+        let (server_send, server_recv) = unbounded_channel::<(RequestId, BackendServerCommand)>();
+        let (server_response_send, server_response_recv) =
+            unbounded_channel::<(RequestId, Response)>();
+        let runtime_path = self.runtime_dir.clone();
+        let bus_name = self.instance.bus_name();
+        let ipc_access = self.config.ipc.clone();
+        let readiness = self.readiness.clone();
+        let restart_requested = self.restart_requested.clone();
 
         let mut app_recv_async = self.init_abi_app_channel();
 
         // load layout manager and init modules
+        systemd::status("Loading modules");
         self.load_layout_manager(config_dir);
-        self.load_layout_config();
+        let mut startup_rejected: Vec<Rejected> =
+            self.load_layout_config(None).err().into_iter().collect();
 
         let module_order = self.load_modules(config_dir);
-        self.load_configs(config_dir);
+        startup_rejected.extend(self.load_main_config(config_dir).err());
+        startup_rejected.extend(self.load_module_configs(&module_order, &Config::default()));
         self.init_loaded_modules(&module_order);
+        self.module_order = module_order;
 
         // init layout manager and send start signal
         let (start_signal_tx, start_signal_rx) = tokio::sync::broadcast::channel::<()>(1);
@@ -88,9 +156,12 @@ impl App {
             .map(|d| d.open_debugger_at_start)
             .unwrap_or(false);
         let layout = self.layout.clone().unwrap();
+        let layout_readiness = self.readiness.clone();
         self.application.connect_activate(move |_app| {
             log::info!("Loading LayoutManager");
             layout.blocking_lock().1.init();
+            layout_readiness.mark(Stage::LayoutInitialized);
+            systemd::start_watchdog();
             start_signal_tx.send(()).unwrap();
             gtk::Window::set_interactive_debugging(open_debugger);
         });
@@ -99,8 +170,20 @@ impl App {
         let mut start_signal = start_signal_rx.resubscribe();
         let layout = self.layout.clone().unwrap();
         let module_map = self.module_map.clone();
+        let event_send = self.event_send.clone();
+        let restore = Rc::new(RefCell::new(self.restore_state.take().map(Restore::new)));
+        let notifications = self.notifications.clone();
         glib::MainContext::default().spawn_local(async move {
             start_signal.recv().await.unwrap();
+            if restore.borrow().is_some() {
+                let restore = restore.clone();
+                glib::timeout_add_local_once(restart::RESTORE_TIMEOUT, move || {
+                    if let Some(restore) = restore.take() {
+                        let missing: Vec<String> = restore.missing().collect();
+                        log::warn!("these activities were not registered again after the restart: {}", missing.join(", "));
+                    }
+                });
+            }
 
             // TODO check if there are too many tasks on the UI thread and it begins to lag
             while let Some(command) = app_recv_async.recv().await {
@@ -130,18 +213,48 @@ impl App {
                             continue;
                         }
 
-                        layout
-                            .lock()
-                            .await
-                            .1
-                            .add_activity(&activity_id, activity.into());
+                        let notification = restore
+                            .borrow_mut()
+                            .as_mut()
+                            .and_then(|restore| restore.restore_activity(&activity_id, &activity));
+
+                        let mode_event_send = event_send.clone();
+                        let id = activity_id.clone();
+                        let last_mode = Cell::new(activity.property::<ActivityMode>("mode"));
+                        activity.connect_notify_local(Some("mode"), move |activity, _| {
+                            let mode = activity.property::<ActivityMode>("mode");
+                            // the mode is also set again to update the size after a config change
+                            if last_mode.replace(mode) != mode {
+                                let _ = mode_event_send.send(Event::mode_changed(&id, mode));
+                            }
+                        });
+
+                        let mut layout = layout.lock().await;
+                        layout.1.add_activity(&activity_id, activity.clone().into());
                         log::info!("registered activity on {}", activity_id.module());
+                        let _ = event_send.send(Event::activity_added(&activity_id));
+                        if let Some(notification) = notification {
+                            if let Ok(mode) = ActivityMode::try_from(notification.mode) {
+                                notifications.requested(&activity_id, &activity, mode, notification.remaining_ms);
+                                layout.1.activity_notification(&activity_id, notification.mode, notification.remaining_ms.into());
+                            }
+                        }
+                        let restored = restore.borrow().as_ref().map(|restore| {
+                            restore.restore_order(&activity_id, &mut layout.1);
+                            restore.is_done()
+                        });
+                        if restored == Some(true) {
+                            log::info!("restored the state of the activities");
+                            restore.take();
+                        }
                     }
                     UIServerCommand::RemoveActivity { activity_id } => {
                         let mut layout = layout.lock().await;
                         if layout.1.get_activity(&activity_id).is_some(){
                             layout.1.remove_activity(&activity_id);
+                            notifications.remove(&activity_id);
                             log::info!("unregistered activity on {}", activity_id.module());
+                            let _ = event_send.send(Event::activity_removed(&activity_id));
                         }else{
                             log::warn!("error removing activity, not found: {:?}", activity_id);
                         }
@@ -156,16 +269,35 @@ impl App {
                             continue;
                         }
                         let layout = layout.lock().await;
-                        if layout.1.get_activity(&activity_id).is_none(){
+                        let Some(widget) = layout.1.get_activity(&activity_id).into_option() else {
                             continue;
+                        };
+                        if let Ok(widget) = TryInto::<Widget>::try_into(widget) {
+                            notifications.requested(&activity_id, &widget, ActivityMode::try_from(mode).unwrap(), duration.into_option());
                         }
                         layout.1.activity_notification(&activity_id, mode, duration);
+                        let _ = event_send.send(Event::notification_requested(
+                            &activity_id,
+                            ActivityMode::try_from(mode).unwrap(),
+                            duration.into(),
+                        ));
                     }
                 }
             }
         });
 
+        self.register_actions(server_send.clone());
+
+        match ConfigWatcher::new(server_send.clone()) {
+            Ok(watcher) => self.config_watcher = Some(watcher),
+            Err(err) => log::warn!(
+                "Failed to start config file watcher, restart dynisland to get automatic config updates: {err}"
+            ),
+        }
+
         let app = self.application.clone();
+        let event_send = self.event_send.clone();
+        let startup_events = self.startup_events.clone();
         let mut start_signal = start_signal_rx.resubscribe();
         let conf_dir = config_dir.to_path_buf();
         //server command consumer
@@ -182,6 +314,7 @@ impl App {
             };
 
             log::info!("Using renderer: {}", renderer_name);
+            self.renderer = renderer_name.to_string();
 
             //init css providers
             let fallback_provider = gtk::CssProvider::new();
@@ -200,7 +333,14 @@ impl App {
                 &self.css_provider,
                 gtk::STYLE_PROVIDER_PRIORITY_USER,
             );
-            self.load_css(&conf_dir); //load user's scss
+            startup_rejected.extend(self.load_css(&conf_dir).err()); //load user's scss
+            self.watch_config_files(&conf_dir);
+            self.remember_known_good(&startup_rejected);
+            let startup_errors: Vec<String> = startup_rejected
+                .into_iter()
+                .map(|rejected| rejected.error)
+                .collect();
+            self.show_config_errors(&startup_errors);
 
             self.restart_producer_runtimes(); // start producers
 
@@ -208,20 +348,47 @@ impl App {
                 .await;
         });
 
-        let _wathcer = start_config_dir_watcher(server_send.clone(), &config_dir);
-
         //start application
         app.register(None as Option<&gtk::gio::Cancellable>)?;
-        let running = app.is_remote();
-        if running {
-            log::error!("dynisland is already running");
-        } else {
-            start_ipc_server(runtime_path.clone(), server_send, server_response_recv);
+        if app.is_remote() {
+            let message = format!(
+                "dynisland is already running, {} is owned by another process",
+                app.application_id().unwrap_or_default()
+            );
+            readiness.fail(ErrorKind::AlreadyRunning, message.clone());
+            bail!(message);
+        }
+        let signal_server_send = server_send.clone();
+        let server = IpcServer {
+            server_send,
+            pending: PendingRequests::default(),
+            access: ipc_access,
+            event_send,
+            startup_events,
+            readiness,
+        };
+        start_ipc_server(runtime_path.clone(), bus_name, server, server_response_recv);
+        // shut down like with `dynisland kill` (also used by `systemctl stop`), SIGKILL is the fallback if the UI is stuck
+        for signal in [Signal::SIGTERM, Signal::SIGINT] {
+            let app = app.clone();
+            let server_send = signal_server_send.clone();
+            glib::unix_signal_add_local(signal as i32, move || {
+                log::info!("received {signal}, quitting");
+                if server_send
+                    .send((INTERNAL_REQUEST_ID, BackendServerCommand::Stop))
+                    .is_err()
+                {
+                    app.quit();
+                }
+                glib::ControlFlow::Continue
+            });
         }
         app.run_with_args::<String>(&[]);
-        if !running {
-            std::fs::remove_file(runtime_path.join("dynisland.sock"))?;
+        // the process is replaced by the new daemon, for systemd it's still running
+        if !restart_requested.get() {
+            systemd::stopping();
         }
+        let _ = std::fs::remove_file(runtime_path.join("dynisland.sock"));
         Ok(())
     }
 
@@ -245,132 +412,276 @@ impl App {
 
     async fn start_backend_server(
         mut self,
-        mut server_recv: tokio::sync::mpsc::UnboundedReceiver<BackendServerCommand>,
-        server_response_send: tokio::sync::mpsc::UnboundedSender<Option<String>>,
+        mut server_recv: tokio::sync::mpsc::UnboundedReceiver<(RequestId, BackendServerCommand)>,
+        server_response_send: tokio::sync::mpsc::UnboundedSender<(RequestId, Response)>,
         config_dir: std::path::PathBuf,
     ) {
-        while let Some(command) = server_recv.recv().await {
+        while let Some((id, command)) = server_recv.recv().await {
             match command {
                 BackendServerCommand::ReloadConfig => {
                     log::info!("Reloading Config");
-                    //TODO split config and css reload (producers don't need to be restarted if only css changed)
-
-                    // without this sleep, reading the config file sometimes gives an empty file.
-                    glib::timeout_future(std::time::Duration::from_millis(50)).await;
-                    self.load_configs(&config_dir);
-                    self.update_general_configs();
-                    self.load_layout_config();
-                    self.load_css(&config_dir);
-
-                    self.restart_producer_runtimes();
+                    systemd::reloading();
+                    self.reload_config(&config_dir);
+                    let _ = server_response_send.send((id, self.reload_response()));
+                }
+                BackendServerCommand::RollbackConfig => {
+                    let response = match self.rollback_config(&config_dir) {
+                        Ok(restored) => {
+                            systemd::reloading();
+                            self.reload_config(&config_dir);
+                            let restored: Vec<String> = restored
+                                .iter()
+                                .map(|path| path.display().to_string())
+                                .collect();
+                            Response::Message(format!(
+                                "restored {}, the rejected files were moved to *{}",
+                                restored.join(", "),
+                                reload::REJECTED_SUFFIX
+                            ))
+                        }
+                        Err(err) => Response::error(ErrorKind::CommandFailed, format!("{err:#}")),
+                    };
+                    let _ = server_response_send.send((id, response));
                 }
                 BackendServerCommand::Stop => {
                     log::info!("Quitting");
-                    let _ = server_response_send.send(None);
+                    systemd::stopping();
+                    let report = self.shutdown(id, &server_response_send).await;
+                    let _ = server_response_send.send((id, Response::Shutdown(report)));
+                    self.application.quit();
+                }
+                BackendServerCommand::Restart => {
+                    log::info!("Restarting");
+                    let state_path = self.runtime_dir.join(restart::STATE_FILE_NAME);
+                    // without the state it would just be a worse `kill` and `daemon`
+                    if let Err(err) = self.save_state().await.save(&state_path) {
+                        let message = format!("failed to save the state, not restarting: {err:#}");
+                        log::error!("{message}");
+                        let _ = server_response_send
+                            .send((id, Response::error(ErrorKind::Internal, message)));
+                        continue;
+                    }
+                    systemd::reloading();
+                    let report = self.shutdown(id, &server_response_send).await;
+                    let _ = server_response_send.send((id, Response::Shutdown(report)));
+                    self.restart_requested.set(true);
                     self.application.quit();
                 }
                 BackendServerCommand::OpenInspector => {
                     log::info!("Opening inspector");
-                    let _ = server_response_send.send(None);
+                    let _ = server_response_send.send((id, Response::Ok));
                     gtk::Window::set_interactive_debugging(true);
                 }
-                BackendServerCommand::ActivityNotification(id, mode, duration) => {
+                BackendServerCommand::ActivityNotification(activity_id, mode, duration) => {
+                    if !self
+                        .module_map
+                        .lock()
+                        .await
+                        .contains_key(activity_id.module())
+                    {
+                        let message = format!("module {} is not loaded", activity_id.module());
+                        let _ = server_response_send
+                            .send((id, Response::error(ErrorKind::ModuleNotFound, message)));
+                        continue;
+                    }
+                    let activity_exists = match self.layout.clone() {
+                        Some(layout) => layout.lock().await.1.get_activity(&activity_id).is_some(),
+                        None => false,
+                    };
+                    if !activity_exists {
+                        let message = format!("activity {activity_id} is not registered");
+                        let _ = server_response_send
+                            .send((id, Response::error(ErrorKind::ActivityNotFound, message)));
+                        continue;
+                    }
                     if let Err(err) =
                         self.app_send
                             .clone()
                             .unwrap()
                             .send(UIServerCommand::RequestNotification {
-                                activity_id: id,
+                                activity_id,
                                 mode: mode as u8,
                                 duration: ROption::from(duration),
                             })
                     {
-                        let _ = server_response_send.send(Some(err.to_string()));
+                        let _ = server_response_send
+                            .send((id, Response::error(ErrorKind::Internal, err.to_string())));
                         log::error!("{err}");
                     } else {
-                        let _ = server_response_send.send(None);
+                        let _ = server_response_send.send((id, Response::Ok));
                     }
                 }
+                BackendServerCommand::Status => {
+                    let status = self.status().await;
+                    let _ = server_response_send.send((id, Response::Status(Box::new(status))));
+                }
                 BackendServerCommand::ListActivities => match self.layout.clone() {
                     Some(layout) => {
                         let activities = layout.lock().await.1.list_activities();
-                        let mut response = String::new();
-                        for activity in activities {
-                            response += &activity.to_string();
-                            response += "\n";
-                        }
-                        let _ = server_response_send.send(Some(response));
+                        let response = activities
+                            .into_iter()
+                            .map(|activity| ActivityInfo {
+                                module: activity.module().to_string(),
+                                activity: activity.activity().to_string(),
+                                metadata: ActivityMetadataInfo {
+                                    window: activity.metadata().window_name(),
+                                },
+                            })
+                            .collect();
+                        let _ = server_response_send.send((id, Response::Activities(response)));
                     }
                     None => {
-                        let _ = server_response_send.send(Some("no layout loaded".to_string()));
+                        let _ = server_response_send
+                            .send((id, Response::error(ErrorKind::NoLayout, "no layout loaded")));
                     }
                 },
                 BackendServerCommand::ListLoadedModules => {
-                    let mut response = String::new();
                     let mod_map = self.module_map.lock().await;
-                    for module in mod_map.keys() {
-                        response += &format!("{module}\n");
-                    }
-                    let _ = server_response_send.send(Some(response));
+                    let response = mod_map
+                        .keys()
+                        .map(|name| ModuleInfo {
+                            name: name.clone(),
+                            origin: self
+                                .module_origins
+                                .get(name)
+                                .cloned()
+                                .unwrap_or(ModuleOrigin::Embedded),
+                        })
+                        .collect();
+                    let _ = server_response_send.send((id, Response::Modules(response)));
                 }
                 BackendServerCommand::ModuleCliCommand(module_name, args) => {
                     match self.module_map.lock().await.get(&module_name) {
                         Some(module) => {
                             let response = match module.cli_command(args.into()) {
-                                ROk(response) => response.into_string(),
-                                RErr(err) => format!("Error:\n{err}"),
+                                ROk(response) => Response::ModuleOutput {
+                                    module: module_name.clone(),
+                                    output: response.into_string(),
+                                },
+                                RErr(err) => {
+                                    Response::error(ErrorKind::CommandFailed, err.to_string())
+                                }
                             };
-                            let _ = server_response_send.send(Some(response));
+                            let _ = server_response_send.send((id, response));
                         }
                         None => {
-                            let _ = server_response_send.send(Some("module not found".to_string()));
+                            let message = format!("module {module_name} not found");
+                            let _ = server_response_send
+                                .send((id, Response::error(ErrorKind::ModuleNotFound, message)));
                         }
                     }
                 }
                 BackendServerCommand::LayoutCliCommand(args) => {
-                    let layout = self.layout.clone().unwrap();
-                    let response = match layout.lock().await.1.cli_command(RString::from(args)) {
-                        ROk(response) => response.into_string(),
-                        RErr(err) => format!("Error:\n{err}"),
+                    let Some(layout) = self.layout.clone() else {
+                        let _ = server_response_send
+                            .send((id, Response::error(ErrorKind::NoLayout, "no layout loaded")));
+                        continue;
+                    };
+                    let layout = layout.lock().await;
+                    let response = match layout.1.cli_command(RString::from(args)) {
+                        ROk(response) => Response::LayoutOutput {
+                            layout: LayoutInfo {
+                                name: layout.0.clone(),
+                                windows: layout
+                                    .1
+                                    .list_windows()
+                                    .into_iter()
+                                    .map(|window| window.into_string())
+                                    .collect(),
+                            },
+                            output: response.into_string(),
+                        },
+                        RErr(err) => Response::error(ErrorKind::CommandFailed, err.to_string()),
                     };
-                    let _ = server_response_send.send(Some(response));
+                    let _ = server_response_send.send((id, response));
                 }
             }
         }
     }
 
-    pub fn load_css(&mut self, config_dir: &Path) {
-        let css_content = grass::from_path(
-            config_dir.join("dynisland.scss"),
-            &grass::Options::default(),
-        );
+    /// Returns whether the stylesheet changed. If it doesn't compile the previous one is kept,
+    /// the error is already logged. Without `dynisland.scss` only the default style is used
+    pub fn load_css(&mut self, config_dir: &Path) -> Result<bool, Rejected> {
+        let path = config_dir.join("dynisland.scss");
+        if matches!(std::fs::metadata(&path), Err(err) if err.kind() == io::ErrorKind::NotFound) {
+            log::info!("{} doesn't exist, using the default style", path.display());
+            self.stylesheet_files = Vec::new();
+            self.stylesheet_sources = Vec::new();
+            if self.stylesheet.is_empty() {
+                return Ok(false);
+            }
+            self.css_provider.load_from_string("");
+            self.stylesheet = String::new();
+            return Ok(true);
+        }
+        let fs = RecordingFs::default();
+        let css_content = grass::from_path(&path, &grass::Options::default().fs(&fs));
+        // also when it doesn't compile, the file with the error has to be watched
+        self.stylesheet_files = fs.read.into_inner();
+        if css_content.is_ok() {
+            self.stylesheet_sources = fs.content.into_inner();
+        }
         match css_content {
+            Ok(content) if content == self.stylesheet => Ok(false),
             Ok(content) => {
-                self.css_provider //TODO maybe save previous state before trying to update
-                    .load_from_string(&content);
+                self.css_provider.load_from_string(&content);
+                self.stylesheet = content;
+                Ok(true)
             }
             Err(err) => {
-                log::warn!("failed to parse css: {}", err.to_string());
+                log::error!("failed to parse css, keeping the previous stylesheet: {err}");
+                Err(Rejected::new(
+                    "dynisland.scss",
+                    format!("failed to parse css: {err}"),
+                ))
             }
         }
     }
 
-    fn load_configs(&mut self, config_dir: &Path) {
-        self.config = config::get_config(config_dir);
-        log::debug!("general_config: {:#?}", self.config.general_style_config);
-        for (module_name, module) in self.module_map.blocking_lock().iter_mut() {
+    /// Replaces the config in use with `dynisland.ron` and returns the previous one, the default config is used
+    /// if it doesn't exist. If it doesn't parse the config in use is kept, the error is already logged
+    pub(crate) fn load_main_config(&mut self, config_dir: &Path) -> Result<Config, Rejected> {
+        match config::read_config(config_dir) {
+            Ok((config, source)) => {
+                log::debug!("general_config: {:#?}", config.general_style_config);
+                self.config_source = Some(source);
+                Ok(std::mem::replace(&mut self.config, config))
+            }
+            Err(err)
+                if err
+                    .root_cause()
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|err| err.kind() == io::ErrorKind::NotFound) =>
+            {
+                log::warn!("{err:#}, using the default config");
+                self.config_source = None;
+                Ok(std::mem::take(&mut self.config))
+            }
+            Err(err) => {
+                log::error!("{err:#}, keeping the config in use");
+                Err(Rejected::new("dynisland.ron", format!("{err:#}")))
+            }
+        }
+    }
+
+    /// Sends their config to the modules in `module_names`, a module that rejects it keeps the one in `previous`.
+    /// The errors are already logged
+    pub(crate) fn load_module_configs(
+        &mut self,
+        module_names: &[String],
+        previous: &Config,
+    ) -> Vec<Rejected> {
+        let mut rejected = Vec::new();
+        let mut module_map = self.module_map.blocking_lock();
+        for module_name in module_names {
+            let Some(module) = module_map.get_mut(module_name) else {
+                continue;
+            };
             log::info!("loading config for module: {:#?}", module_name);
             let config_to_parse = self.config.module_config.get(module_name);
             let config_parsed = match config_to_parse {
                 Some(conf) => {
-                    let confs: String = ron::ser::to_string_pretty(&conf, PrettyConfig::default())
-                        .unwrap()
-                        .into();
-                    log::trace!("{module_name} config before strip comments: {}", confs);
-                    let mut confs = confs.replace("\\'", "\'");
-                    if let Err(err) = json_strip_comments::strip(&mut confs) {
-                        log::warn!("failed to strip trailing commas from {module_name} err: {err}");
-                    };
+                    let confs = config::config_string(module_name, conf);
                     log::trace!("{module_name} config: {}", confs);
                     module.update_config(confs.into())
                 }
@@ -379,19 +690,33 @@ impl App {
                     ROk(())
                 }
             };
-            match config_parsed {
-                RErr(err) => {
-                    log::error!("failed to parse config for module {}: {err:?}", module_name)
-                }
-                ROk(()) => {
-                    // log::debug!("{}: {:#?}", module_name, config_to_parse);
+            if let RErr(err) = config_parsed {
+                log::error!(
+                    "failed to parse config for module {module_name}, keeping the previous one: {err:?}"
+                );
+                rejected.push(Rejected::new(
+                    format!("module {module_name}"),
+                    format!("failed to parse config for module {module_name}: {err}"),
+                ));
+                match previous.module_config.get(module_name) {
+                    Some(conf) => {
+                        let _ =
+                            module.update_config(config::config_string(module_name, conf).into());
+                        self.config
+                            .module_config
+                            .insert(module_name.clone(), conf.clone());
+                    }
+                    None => {
+                        self.config.module_config.remove(module_name);
+                    }
                 }
             }
         }
+        rejected
     }
 
     //TODO let the modules handle this, something like module.update_general_config or module.update_config itself
-    fn update_general_configs(&self) {
+    pub(crate) fn update_general_configs(&self) {
         let layout = self.layout.clone().unwrap();
         let layout = layout.blocking_lock();
         let activities = layout.1.list_activities();
@@ -425,33 +750,48 @@ impl App {
         }
     }
 
-    fn load_layout_config(&self) {
+    /// If the layout manager rejects its config it keeps the one in `previous`, the error is already logged
+    pub(crate) fn load_layout_config(&mut self, previous: Option<&Config>) -> Result<(), Rejected> {
         let layout = self.layout.clone().unwrap();
         let mut layout = layout.blocking_lock();
         let layout_name = layout.0.clone();
         if let Some(config) = self.config.layout_configs.get(&layout_name) {
-            let mut confs: String = ron::ser::to_string_pretty(&config, PrettyConfig::default())
-                .unwrap()
-                .into();
-
-            if let Err(err) = json_strip_comments::strip(&mut confs) {
-                log::warn!("failed to strip trailing commas from {layout_name} err: {err}");
-            };
+            let confs = config::config_string(&layout_name, config);
             log::debug!("{layout_name} config: {}", confs);
             match layout.1.update_config(confs.into()) {
                 ROk(()) => {
                     log::info!("loaded layout config for {layout_name}");
                 }
                 RErr(err) => {
-                    log::error!("failed to parse layout config for {layout_name}, {err}");
+                    log::error!("failed to parse layout config for {layout_name}, keeping the previous one: {err}");
+                    let previous =
+                        previous.and_then(|config| config.layout_configs.get(&layout_name));
+                    match previous {
+                        Some(conf) => {
+                            let _ = layout
+                                .1
+                                .update_config(config::config_string(&layout_name, conf).into());
+                            self.config
+                                .layout_configs
+                                .insert(layout_name.clone(), conf.clone());
+                        }
+                        None => {
+                            self.config.layout_configs.remove(&layout_name);
+                        }
+                    }
+                    return Err(Rejected::new(
+                        format!("layout {layout_name}"),
+                        format!("failed to parse layout config for {layout_name}: {err}"),
+                    ));
                 }
             }
         } else {
             log::info!("no layout config found for {layout_name}, using Default");
         }
+        Ok(())
     }
 
-    fn restart_producer_runtimes(&self) {
+    pub(crate) fn restart_producer_runtimes(&self) {
         for module in self.module_map.blocking_lock().values_mut() {
             module.restart_producers();
         }
@@ -485,7 +825,7 @@ impl App {
         // get all the loadable Module configs
         let mod_defs = crate::module_loading::get_module_definitions(&self.config_dir);
         let mut module_configs: Vec<(String, RResult<RString, RBoxError>)> = Vec::new();
-        for (mod_name, mod_constructor) in mod_defs {
+        for (mod_name, (mod_constructor, _)) in mod_defs {
             match mod_constructor(self.app_send.clone().unwrap()) {
                 ROk(built_mod) => {
                     module_configs.push((mod_name, built_mod.default_config()));
@@ -566,7 +906,7 @@ impl Default for App {
     fn default() -> Self {
         // let (hdl, shutdown) = get_new_tokio_rt();
         let flags = gtk::gio::ApplicationFlags::default();
-        let app = gtk::Application::new(Some("com.github.cr3eperall.dynisland"), flags);
+        let app = gtk::Application::new(Some(instance::APPLICATION_ID), flags);
         App {
             application: app,
             module_map: Rc::new(Mutex::new(HashMap::new())),
@@ -577,48 +917,35 @@ impl Default for App {
             config: config::Config::default(),
             css_provider: gtk::CssProvider::new(),
             config_dir: config::get_default_config_path(),
+            instance: Instance::default(),
+            module_origins: HashMap::new(),
+            module_order: Vec::new(),
+            event_send: tokio::sync::broadcast::channel(events::EVENT_CHANNEL_CAPACITY).0,
+            startup_events: Vec::new(),
+            readiness: Readiness::default(),
+            restore_state: None,
+            notifications: Notifications::default(),
+            runtime_dir: Instance::default().runtime_dir(&config::Config::default()),
+            restart_requested: Rc::new(Cell::new(false)),
+            started_at: Instant::now(),
+            renderer: String::from("unknown"),
+            last_reload: None,
+            known_good: KnownGood::default(),
+            error_activity: None,
+            stylesheet: String::new(),
+            stylesheet_files: Vec::new(),
+            config_source: None,
+            stylesheet_sources: Vec::new(),
+            config_watcher: None,
         }
     }
 }
 
-fn start_config_dir_watcher(
-    server_send: tokio::sync::mpsc::UnboundedSender<BackendServerCommand>,
-    config_dir: &Path,
-) -> RecommendedWatcher {
-    log::info!("starting config watcher");
-    let mut watcher =
-        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
-            Ok(evt) => {
-                // log::info!("config event: {:?}",evt.kind);
-                match evt.kind {
-                    notify::EventKind::Modify(notify::event::ModifyKind::Data(_)) => {
-                        log::debug!("Config change detected");
-                        server_send
-                            .send(BackendServerCommand::ReloadConfig)
-                            .expect("Failed to send notification")
-                    }
-                    notify::EventKind::Create(_) => {
-                        // log::info!("file create event");
-                    }
-                    _ => {}
-                }
-                // log::debug!("{evt:?}");
-            }
-            Err(err) => {
-                log::error!("Notify watcher error: {err}")
-            }
-        })
-        .expect("Failed to get file watcher");
-    if let Err(err) = watcher.watch(&config_dir, notify::RecursiveMode::NonRecursive) {
-        log::warn!("Failed to start config file watcher, restart dynisland to get automatic config updates: {err}")
-    }
-    watcher
-}
-
 fn start_ipc_server(
     runtime_path: std::path::PathBuf,
-    server_send: tokio::sync::mpsc::UnboundedSender<BackendServerCommand>,
-    mut server_response_recv: tokio::sync::mpsc::UnboundedReceiver<Option<String>>,
+    bus_name: String,
+    server: IpcServer,
+    mut server_response_recv: tokio::sync::mpsc::UnboundedReceiver<(RequestId, Response)>,
 ) {
     let thread = thread::Builder::new().name("ipc-server".to_string());
     thread
@@ -628,25 +955,37 @@ fn start_ipc_server(
                 .build()
                 .unwrap();
             rt.block_on(async move {
+                let dbus_service = dbus::serve(
+                    bus_name,
+                    server.server_send.clone(),
+                    server.pending.clone(),
+                    server.event_send.clone(),
+                );
+                tokio::spawn(async move {
+                    if let Err(err) = dbus_service.await {
+                        log::warn!("D-Bus service not available: {err}");
+                    }
+                });
                 loop {
                     std::fs::create_dir_all(&runtime_path).expect("invalid runtime path");
                     log::info!(
                         "starting ipc socket at {}",
                         runtime_path.canonicalize().unwrap().to_str().unwrap()
                     );
-                    if let Err(err) = open_socket(
-                        &runtime_path,
-                        server_send.clone(),
-                        &mut server_response_recv,
-                    )
-                    .await
+                    if let Err(err) =
+                        open_socket(&runtime_path, &mut server_response_recv, &server).await
                     {
                         log::error!("socket closed: {err}");
-                        if matches!(
-                            err.downcast::<std::io::Error>().unwrap().kind(),
-                            ErrorKind::AddrInUse
-                        ) {
-                            log::error!("app was already started");
+                        // the socket is only reopened if it fails after the daemon is ready,
+                        // another daemon on the same socket is already excluded by the PID file
+                        if !server.readiness.is_reported() {
+                            server.readiness.fail(
+                                ErrorKind::StartupFailed,
+                                format!("failed to open the socket: {err}"),
+                            );
+                            let _ = server
+                                .server_send
+                                .send((INTERNAL_REQUEST_ID, BackendServerCommand::Stop));
                             break;
                         }
                     } else {
@@ -1,6 +1,5 @@
 use std::path::PathBuf;
 
-use bincode::{Decode, Encode};
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
@@ -12,17 +11,42 @@ pub struct Cli {
 
     #[arg(long, short)]
     pub config_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "DYNISLAND_INSTANCE",
+        help = "Name of the daemon instance to start or to talk to, every instance has its own socket, log and application id"
+    )]
+    pub instance: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Print the responses as JSON objects instead of human readable text"
+    )]
+    pub json: bool,
 }
 
-#[derive(Subcommand, Debug, Serialize, Deserialize, PartialEq, Eq, Encode, Decode)]
+#[derive(Subcommand, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum SubCommands {
     Daemon {
         #[arg(short, long, required = false, default_value_t = false)]
         no_daemonize: bool,
+        #[arg(
+            long,
+            hide = true,
+            help = "State saved by the daemon that is restarting"
+        )]
+        restore_state: Option<PathBuf>,
     },
     Reload,
     Inspector,
     HealthCheck,
+    #[command(
+        about = "Show the pid, uptime, layout, windows, modules and last reload result of the daemon"
+    )]
+    Status,
     ActivityNotification {
         activity_identifier: String,
         #[arg(help = "0: Minimal, 1: Compact, 2: Expanded, 3: Overlay")]
@@ -34,10 +58,24 @@ pub enum SubCommands {
         #[arg(short, long, required = false, default_value_t = false)]
         no_daemonize: bool,
     },
+    #[command(
+        about = "Print the default config, with the defaults of every module and layout manager found"
+    )]
     DefaultConfig {
-        // #[arg(short, long, required = false, default_value_t = false)]
-        #[arg(skip = false)]
+        #[arg(
+            short,
+            long,
+            required = false,
+            default_value_t = false,
+            help = "Add the missing options to dynisland.ron, keeping the values already set. The previous file is kept as dynisland.ron.bak"
+        )]
         replace_current_config: bool,
+        #[arg(
+            long,
+            requires = "replace_current_config",
+            help = "Print the changes instead of writing them"
+        )]
+        dry_run: bool,
     },
     ListActivities,
     ListLoadedModules,
@@ -49,4 +87,85 @@ pub enum SubCommands {
     Layout {
         args: Vec<String>,
     },
+    #[command(about = "Print the daemon events as newline-delimited JSON")]
+    Subscribe {
+        #[arg(short, long, help = "Only show events from this module")]
+        module: Vec<String>,
+        #[arg(
+            short,
+            long,
+            help = "Only show events from this activity (activity@module)"
+        )]
+        activity: Vec<String>,
+    },
+    #[command(
+        about = "Change the log filter of the running daemon, with the RUST_LOG syntax (e.g. dynisland_core=debug,music_module=trace)"
+    )]
+    LogFilter {
+        #[arg(
+            help = "Directives to add to the filter in use, without it the filter in use is printed"
+        )]
+        filter: Option<String>,
+        #[arg(
+            long,
+            conflicts_with = "filter",
+            help = "Go back to the filter the daemon was started with"
+        )]
+        reset: bool,
+    },
+    #[command(about = "Print the log of the daemon")]
+    Logs {
+        #[arg(short, long, help = "Keep printing the new lines")]
+        follow: bool,
+        #[arg(
+            short,
+            long,
+            value_parser = ["error", "warn", "info", "debug", "trace"],
+            help = "Only show the lines at this level or more severe"
+        )]
+        level: Option<String>,
+    },
+    #[command(
+        about = "Check the environment, the config and the installed modules without the daemon"
+    )]
+    Doctor,
+    #[command(about = "Manage the config file")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    #[command(about = "Write a systemd user unit that starts the daemon with Type=notify")]
+    InstallService {
+        #[arg(
+            short,
+            long,
+            help = "Where to write the unit, defaults to ~/.config/systemd/user/dynisland.service"
+        )]
+        output: Option<PathBuf>,
+        #[arg(short, long, help = "Replace the unit if it already exists")]
+        force: bool,
+        #[arg(long, help = "Print the unit instead of writing it")]
+        print: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ConfigCommand {
+    #[command(
+        about = "Check a config without the daemon, with the configs of the modules and layout managers. Exits with 1 if there are errors",
+        long_about = "Check a config without the daemon, with the configs of the modules and layout managers. Exits with 1 if there are errors.\n\nThe modules and layout managers are created to check their configs, some of them can start their work (e.g. connect to a service) when they are created, use --no-modules to only check the main config"
+    )]
+    Validate {
+        #[arg(help = "Config file or directory to check, defaults to the dynisland.ron in use")]
+        path: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Don't create the modules and layout managers, their configs are not checked"
+        )]
+        no_modules: bool,
+    },
+    #[command(
+        about = "Write back the config files the daemon last applied without errors, the replaced ones are kept as *.rejected"
+    )]
+    Rollback,
 }
@@ -0,0 +1,621 @@
+//! Non-destructive update of `dynisland.ron`, used by `dynisland default-config --replace-current-config`.
+//!
+//! The options that are missing from the current config are taken from the default config and inserted
+//! in the text of the file, the rest of it (values, comments and options dynisland doesn't know) is kept as it is.
+//! The previous file is kept as `dynisland.ron.bak`.
+//! If the options can't be inserted the file is not written, the diff of the rewritten config has to be applied by hand.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use dynisland_core::ron;
+use ron::{extensions::Extensions, ser::PrettyConfig, Value};
+use serde::Serialize;
+
+use super::{
+    source::{self, Container},
+    Config,
+};
+
+/// Lines of context around the changes in [`ConfigMerge::diff`]
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigMerge {
+    pub path: PathBuf,
+    /// Options added from the default config, like `module_config.music_module.scrolling`
+    pub added: Vec<String>,
+    /// Options of the current config that are not dynisland options, they are kept as they are
+    pub unknown: Vec<String>,
+    /// Whether the options were inserted in the current file, otherwise it can only be rewritten
+    /// without its comments and unknown options, so it's not written
+    pub in_place: bool,
+    #[serde(skip)]
+    current: String,
+    #[serde(skip)]
+    merged: String,
+}
+
+impl ConfigMerge {
+    /// Merges `default` into the config at `path`, nothing is written yet.
+    ///
+    /// The config must be valid, otherwise its values couldn't be kept
+    pub fn new(path: &Path, default: &Config) -> Result<Self> {
+        let current = match std::fs::read_to_string(path) {
+            Ok(current) => current,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        Self::from_text(path, current, default)
+    }
+
+    /// Like [`new`](Self::new), with the content of the config
+    fn from_text(path: &Path, current: String, default: &Config) -> Result<Self> {
+        let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+        let (config, current_value) = if current.is_empty() {
+            (default.clone(), Value::Map(ron::Map::new()))
+        } else {
+            let config: Config = options.from_str(&current).with_context(|| {
+                format!(
+                    "failed to parse {}, fix it before merging the default config",
+                    path.display()
+                )
+            })?;
+            let value: Value = options
+                .from_str(&current)
+                .with_context(|| format!("failed to parse {}", path.display()))?;
+            (merge_config(config, default), value)
+        };
+
+        let merged = to_ron_string(&config)?;
+        let merged_value: Value = options
+            .from_str(&merged)
+            .with_context(|| "the merged config is not valid")?;
+        if options.from_str::<Config>(&merged).is_err() {
+            bail!("the merged config is not valid");
+        }
+        let mut added = Vec::new();
+        missing_keys(&merged_value, &current_value, "", &mut added);
+        let mut unknown = Vec::new();
+        missing_keys(&current_value, &merged_value, "", &mut unknown);
+
+        // an empty file has nothing to keep
+        let inserted = if current.is_empty() {
+            Some(merged.clone())
+        } else {
+            insert_missing(&current, &merged, &added).filter(|inserted| {
+                let Ok(inserted_value) = options.from_str::<Value>(inserted) else {
+                    return false;
+                };
+                let mut lost = Vec::new();
+                missing_keys(&merged_value, &inserted_value, "", &mut lost);
+                missing_keys(&current_value, &inserted_value, "", &mut lost);
+                lost.is_empty() && options.from_str::<Config>(inserted).is_ok()
+            })
+        };
+        let in_place = inserted.is_some();
+        Ok(Self {
+            path: path.to_path_buf(),
+            added,
+            unknown,
+            in_place,
+            current,
+            merged: inserted.unwrap_or(merged),
+        })
+    }
+
+    /// Whether the current config already has every option
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+    }
+
+    pub fn backup_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".bak");
+        PathBuf::from(path)
+    }
+
+    /// Writes the merged config, the current one is copied to [`backup_path`](Self::backup_path) first.
+    /// Returns the path of the backup, `None` if there was no config
+    pub fn write(&self) -> Result<Option<PathBuf>> {
+        if !self.in_place {
+            bail!(
+                "the options can't be added to {} without rewriting it, apply the diff by hand",
+                self.path.display()
+            );
+        }
+        let backup = if self.current.is_empty() {
+            None
+        } else {
+            let backup = self.backup_path();
+            std::fs::write(&backup, &self.current)
+                .with_context(|| format!("failed to write {}", backup.display()))?;
+            Some(backup)
+        };
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        super::write_config_file(&self.path, self.merged.as_bytes())?;
+        Ok(backup)
+    }
+
+    /// Unified diff between the current config and the merged one
+    pub fn diff(&self) -> String {
+        let name = self.path.display();
+        let mut diff = format!("--- {name}\n+++ {name} (merged)\n");
+        let current: Vec<&str> = self.current.lines().collect();
+        let merged: Vec<&str> = self.merged.lines().collect();
+        for hunk in hunks(&diff_lines(&current, &merged)) {
+            diff += &hunk;
+        }
+        diff
+    }
+}
+
+/// An option to insert: its position in the merged config, its key and its value
+type Insertion<'a> = (usize, &'a str, String);
+
+/// Inserts the options of `merged` at the paths in `added` into `current`, the rest of the text is kept as it is.
+///
+/// `None` if one of them can't be placed
+fn insert_missing(current: &str, merged: &str, added: &[String]) -> Option<String> {
+    // the options added to the same struct or map are inserted together
+    let mut insertions: Vec<(Container, Vec<Insertion>)> = Vec::new();
+    for key_path in added {
+        let path: Vec<&str> = key_path.split('.').collect();
+        let (key, parents) = path.split_last()?;
+        let container = match parents {
+            [] => source::root(current)?,
+            parents => source::container_at(current, source::find(current, parents)?.value.start)?,
+        };
+        let entry = source::find(merged, &path)?;
+        // the lines of the value are indented like in the merged config, relative to its key
+        let merged_indent = source::leading_whitespace(merged, entry.value.start);
+        let value = merged[entry.value.clone()].replace(&format!("\n{merged_indent}"), "\n");
+        match insertions
+            .iter_mut()
+            .find(|(other, _)| other.open == container.open)
+        {
+            Some((_, options)) => options.push((entry.value.start, key, value)),
+            None => insertions.push((container, vec![(entry.value.start, key, value)])),
+        }
+    }
+
+    let mut edits: Vec<(usize, String)> = Vec::new();
+    for (container, mut options) in insertions {
+        // in the order of the merged config
+        options.sort_by_key(|(position, ..)| *position);
+        edits.extend(insertion(current, &container, &options));
+    }
+    // from the end, so the positions of the other edits are still valid,
+    // the edits at the same position are inserted in reverse so they end up in order
+    edits.reverse();
+    edits.sort_by_key(|(position, _)| std::cmp::Reverse(*position));
+    let mut inserted = current.to_string();
+    for (position, text) in edits {
+        inserted.insert_str(position, &text);
+    }
+    Some(inserted)
+}
+
+/// The text to insert to add `options` at the end of `container`, with the indentation of its entries
+fn insertion(current: &str, container: &Container, options: &[Insertion]) -> Vec<(usize, String)> {
+    let key = |key: &str| match container.is_map(current) {
+        true => format!("\"{key}\""),
+        false => key.to_string(),
+    };
+    let mut edits = Vec::new();
+    let last = container.entries.last();
+    // the comma after the last entry, if there is one
+    let comma = last.and_then(|last| {
+        let after = source::skip_trivia(current, last.value.end);
+        (current.as_bytes()[after] == b',').then_some(after + 1)
+    });
+    if let (Some(last), None) = (last, comma) {
+        edits.push((last.value.end, ",".to_string()));
+    }
+
+    if !current[container.open..container.close].contains('\n') {
+        // `(a: 1)` becomes `(a: 1, b: 2)`
+        let options: Vec<String> = options
+            .iter()
+            .map(|(_, name, value)| format!("{}: {value}", key(name)))
+            .collect();
+        let separator = if last.is_some() { " " } else { "" };
+        edits.push((
+            container.close,
+            format!("{separator}{}", options.join(", ")),
+        ));
+        return edits;
+    }
+
+    let container_indent = source::leading_whitespace(current, container.open);
+    let indent = last
+        .and_then(|last| {
+            let start = last.key.as_ref().unwrap_or(&last.value).start;
+            source::line_indent(current, start)
+        })
+        .map_or_else(|| format!("{container_indent}    "), str::to_string);
+    // after the last entry and the comments on its line, or after the opening bracket
+    let position = match comma.or(last.map(|last| last.value.end)) {
+        Some(after) => {
+            let line_end = current[after..]
+                .find('\n')
+                .map_or(current.len(), |newline| after + newline);
+            let rest = current[after..line_end].trim();
+            if rest.is_empty() || rest.starts_with("//") {
+                line_end
+            } else {
+                after
+            }
+        }
+        None => container.open + 1,
+    };
+    let mut text = String::new();
+    for (_, name, value) in options {
+        let value = value.replace('\n', &format!("\n{indent}"));
+        let _ = write!(text, "\n{indent}{}: {value},", key(name));
+    }
+    edits.push((position, text));
+    edits
+}
+
+/// Keeps every value of `config`, the layouts and modules it doesn't configure are added from `default`.
+///
+/// Only the configs of the layouts and modules are merged key by key, the other options
+/// are already filled with their defaults when the config is parsed
+pub fn merge_config(mut config: Config, default: &Config) -> Config {
+    for (current, default) in [
+        (&mut config.layout_configs, &default.layout_configs),
+        (&mut config.module_config, &default.module_config),
+    ] {
+        for (name, default) in default {
+            match current.get_mut(name) {
+                Some(current) => merge_value(current, default),
+                None => {
+                    current.insert(name.clone(), default.clone());
+                }
+            }
+        }
+    }
+    config
+}
+
+/// Adds the keys of `default` that are missing in `current`, recursively
+fn merge_value(current: &mut Value, default: &Value) {
+    match (current, default) {
+        (Value::Map(current), Value::Map(default)) => {
+            for (key, default) in default.iter() {
+                match current.remove(key) {
+                    Some(mut value) => {
+                        merge_value(&mut value, default);
+                        current.insert(key.clone(), value);
+                    }
+                    None => {
+                        current.insert(key.clone(), default.clone());
+                    }
+                }
+            }
+        }
+        (Value::Option(Some(current)), Value::Option(Some(default))) => {
+            merge_value(current, default)
+        }
+        (Value::Option(Some(current)), default) => merge_value(current, default),
+        (current, Value::Option(Some(default))) => merge_value(current, default),
+        _ => {}
+    }
+}
+
+/// Paths of the keys of `value` that are not in `other`
+pub(super) fn missing_keys(value: &Value, other: &Value, path: &str, missing: &mut Vec<String>) {
+    let (Some(map), Some(other)) = (as_map(value), as_map(other)) else {
+        return;
+    };
+    for (key, value) in map.iter() {
+        let key_path = match path {
+            "" => key_name(key),
+            path => format!("{path}.{}", key_name(key)),
+        };
+        match other.iter().find(|(other_key, _)| *other_key == key) {
+            Some((_, other)) => missing_keys(value, other, &key_path, missing),
+            None => missing.push(key_path),
+        }
+    }
+}
+
+fn as_map(value: &Value) -> Option<&ron::Map> {
+    match value {
+        Value::Map(map) => Some(map),
+        Value::Option(Some(value)) => as_map(value),
+        _ => None,
+    }
+}
+
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        key => ron::to_string(key).unwrap_or_else(|_| format!("{key:?}")),
+    }
+}
+
+/// Like the `Display` of [`Config`], but the layouts and modules are sorted by name
+pub fn to_ron_string(config: &Config) -> Result<String> {
+    let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+    let base = Config {
+        layout_configs: HashMap::new(),
+        module_config: HashMap::new(),
+        ..config.clone()
+    };
+    let mut conf_str = "Config".to_owned()
+        + &options
+            .to_string_pretty(&base, PrettyConfig::default())
+            .with_context(|| "failed to serialize the config")?;
+    for (field, configs) in [
+        ("layout_configs", &config.layout_configs),
+        ("module_config", &config.module_config),
+    ] {
+        let mut names: Vec<&String> = configs.keys().collect();
+        names.sort();
+        let mut configs_str = String::from("{\n");
+        for name in names {
+            let value = options
+                .to_string_pretty(&configs[name], PrettyConfig::default())
+                .with_context(|| format!("failed to serialize the config of {name}"))?;
+            configs_str += &format!("\"{name}\": {value},\n")
+                .lines()
+                .map(|l| "        ".to_owned() + l + "\n")
+                .collect::<String>();
+        }
+        configs_str += "    },";
+        conf_str = conf_str.replace(
+            &format!("{field}: {{}},"),
+            &format!("{field}: {configs_str}"),
+        );
+    }
+    Ok(conf_str)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Line diff from the longest common subsequence, the configs are small enough for the quadratic table
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Groups the changes in hunks with [`DIFF_CONTEXT`] lines of context, like `diff -u`
+fn hunks(lines: &[Line]) -> Vec<String> {
+    let changes: Vec<usize> = (0..lines.len())
+        .filter(|&index| !matches!(lines[index], Line::Same(_)))
+        .collect();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for index in changes {
+        let start = index.saturating_sub(DIFF_CONTEXT);
+        let end = (index + DIFF_CONTEXT + 1).min(lines.len());
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+
+    let mut hunks = Vec::new();
+    // line numbers in the old and new file at the start of `lines[index]`
+    let position = |index: usize| {
+        lines[..index]
+            .iter()
+            .fold((0, 0), |(old, new), line| match line {
+                Line::Same(_) => (old + 1, new + 1),
+                Line::Removed(_) => (old + 1, new),
+                Line::Added(_) => (old, new + 1),
+            })
+    };
+    for (start, end) in ranges {
+        let (old_start, new_start) = position(start);
+        let (old_end, new_end) = position(end);
+        let mut hunk = String::new();
+        let _ = writeln!(
+            hunk,
+            "@@ -{},{} +{},{} @@",
+            old_start + 1,
+            old_end - old_start,
+            new_start + 1,
+            new_end - new_start
+        );
+        for line in &lines[start..end] {
+            let _ = match line {
+                Line::Same(line) => writeln!(hunk, " {line}"),
+                Line::Removed(line) => writeln!(hunk, "-{line}"),
+                Line::Added(line) => writeln!(hunk, "+{line}"),
+            };
+        }
+        hunks.push(hunk);
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURRENT: &str = r#"// my config
+Config(
+    layout: "FallbackLayout", // the layout
+    loaded_modules: ["all"],
+    old_option: true,
+    module_config: {
+        "ClockModule": (format: "%H:%M"),
+    },
+)
+"#;
+
+    fn value(text: &str) -> Value {
+        ron::from_str(text).unwrap()
+    }
+
+    fn default() -> Config {
+        let mut default = Config::default();
+        default.module_config.insert(
+            "ClockModule".to_string(),
+            value(r#"{"format": "%T", "tz": "UTC"}"#),
+        );
+        default
+            .module_config
+            .insert("MusicModule".to_string(), value(r#"{"scrolling": true}"#));
+        default
+    }
+
+    #[test]
+    fn merge_value_keeps_the_current_values() {
+        let mut current = value(r#"{"a": 1, "nested": {"b": 2}, "option": Some({"c": 3})}"#);
+        let default = value(
+            r#"{"a": 10, "added": 4, "nested": {"b": 20, "d": 5}, "option": {"c": 30, "e": 6}}"#,
+        );
+        merge_value(&mut current, &default);
+        assert_eq!(
+            current,
+            value(
+                r#"{"a": 1, "added": 4, "nested": {"b": 2, "d": 5}, "option": Some({"c": 3, "e": 6})}"#
+            )
+        );
+    }
+
+    #[test]
+    fn options_are_inserted_in_the_current_file() {
+        let merge =
+            ConfigMerge::from_text(Path::new("dynisland.ron"), CURRENT.to_string(), &default())
+                .unwrap();
+        assert!(merge.in_place);
+        assert!(merge
+            .added
+            .contains(&"module_config.ClockModule.tz".to_string()));
+        assert!(merge
+            .added
+            .contains(&"module_config.MusicModule".to_string()));
+        assert!(merge.added.contains(&"show_config_errors".to_string()));
+        assert_eq!(merge.unknown, ["old_option"]);
+
+        // everything that was in the file is still there, in the same order,
+        // only the line of ClockModule gets a new option
+        let mut rest = merge.merged.as_str();
+        for line in CURRENT.lines().filter(|line| !line.contains("ClockModule")) {
+            let line = line.trim_end_matches(',');
+            let position = rest
+                .find(line)
+                .unwrap_or_else(|| panic!("{line} is missing"));
+            rest = &rest[position + line.len()..];
+        }
+        assert!(merge
+            .merged
+            .contains(r#""ClockModule": (format: "%H:%M", tz: "UTC"),"#));
+        assert!(merge.merged.contains("\n    show_config_errors: true,\n"));
+        assert!(merge.merged.contains("\n        \"MusicModule\": "));
+
+        // nothing is missing anymore
+        let again =
+            ConfigMerge::from_text(Path::new("dynisland.ron"), merge.merged, &default()).unwrap();
+        assert!(again.is_empty());
+        assert_eq!(again.merged, again.current);
+    }
+
+    #[test]
+    fn empty_file_gets_the_default_config() {
+        let merge =
+            ConfigMerge::from_text(Path::new("dynisland.ron"), String::new(), &default()).unwrap();
+        assert!(merge.in_place);
+        assert_eq!(merge.merged, to_ron_string(&default()).unwrap());
+    }
+
+    #[test]
+    fn write_keeps_a_symlinked_config() {
+        let dir = std::env::temp_dir().join(format!("dynisland-merge-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("dotfiles.ron");
+        let link = dir.join("dynisland.ron");
+        std::fs::write(&target, CURRENT).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let merge = ConfigMerge::new(&link, &default()).unwrap();
+        assert_eq!(merge.write().unwrap(), Some(dir.join("dynisland.ron.bak")));
+        assert!(link.is_symlink());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), merge.merged);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("dynisland.ron.bak")).unwrap(),
+            CURRENT
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diff_lines_keeps_the_common_lines() {
+        let old = ["a", "b", "c", "d"];
+        let new = ["a", "c", "x", "d"];
+        assert_eq!(
+            diff_lines(&old, &new),
+            [
+                Line::Same("a"),
+                Line::Removed("b"),
+                Line::Same("c"),
+                Line::Added("x"),
+                Line::Same("d"),
+            ]
+        );
+        assert!(diff_lines(&old, &old)
+            .iter()
+            .all(|line| matches!(line, Line::Same(_))));
+    }
+
+    #[test]
+    fn hunks_have_context_and_line_numbers() {
+        let old: Vec<String> = (1..=20).map(|line| line.to_string()).collect();
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let mut new = old.clone();
+        new.insert(2, "added");
+        new.remove(16);
+
+        let changes = hunks(&diff_lines(&old, &new));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], "@@ -1,5 +1,6 @@\n 1\n 2\n+added\n 3\n 4\n 5\n");
+        assert_eq!(
+            changes[1],
+            "@@ -13,7 +14,6 @@\n 13\n 14\n 15\n-16\n 17\n 18\n 19\n"
+        );
+        assert!(hunks(&diff_lines(&old, &old)).is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+pub mod merge;
+pub mod source;
+pub mod validate;
+pub mod watcher;
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use dynisland_core::{
+    abi::{glib, log},
+    ron,
+};
+use ron::{extensions::Extensions, ser::PrettyConfig, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::ipc::codec::{self, FrameCodec};
+
+pub const CONFIG_REL_PATH: &str = "dynisland/";
+
+// ron sucks, ~~i need to switch to pkl~~
+// nvm, there are no good pkl crates
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub loaded_modules: Vec<String>,
+    pub layout: Option<String>,
+    pub general_style_config: GeneralConfig,
+    pub layout_configs: HashMap<String, Value>,
+    pub module_config: HashMap<String, Value>,
+    pub ipc: IpcConfig,
+    pub log: LogConfig,
+    /// Show the errors of the config in an activity, until they are fixed
+    pub show_config_errors: bool,
+    pub debug: Option<DebugConfig>,
+}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DebugConfig {
+    pub runtime_path: String,
+    pub open_debugger_at_start: bool,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self {
+            runtime_path: get_default_runtime_path().to_str().unwrap().to_string(),
+            open_debugger_at_start: false,
+        }
+    }
+}
+
+/// IPC socket settings, they are only read at startup.
+///
+/// `allowed_uids` and `allowed_gids` can use the socket besides the user running the daemon,
+/// groups are matched against the primary and the supplementary groups of the client process.
+/// The other users also need to reach the socket, the default runtime directory is inside `$XDG_RUNTIME_DIR`
+/// which only its owner can enter
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct IpcConfig {
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+    /// Maximum size of a request or response in bytes
+    pub max_frame_size: usize,
+    pub read_timeout_ms: u64,
+}
+
+impl Default for IpcConfig {
+    fn default() -> Self {
+        Self {
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+            max_frame_size: codec::DEFAULT_MAX_FRAME_SIZE,
+            read_timeout_ms: codec::DEFAULT_READ_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+impl IpcConfig {
+    pub fn codec(&self) -> FrameCodec {
+        FrameCodec::new(
+            self.max_frame_size,
+            Duration::from_millis(self.read_timeout_ms),
+        )
+    }
+
+    /// Whether other users can connect, the socket permissions are less restrictive in this case
+    pub fn is_shared(&self) -> bool {
+        !self.allowed_uids.is_empty() || !self.allowed_gids.is_empty()
+    }
+}
+
+/// Log settings of the daemon, they are only read at startup.
+///
+/// The log file is rotated every time a detached daemon starts and when it grows over `max_size`,
+/// the previous ones are kept as `dynisland.log.1` (the most recent) up to `dynisland.log.<max_files>`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Defaults to `dynisland.log` in the runtime dir
+    pub path: Option<PathBuf>,
+    /// Size in bytes, 0 only rotates at startup
+    pub max_size: u64,
+    pub max_files: u32,
+    pub format: LogFormat,
+    pub backend: LogBackend,
+    /// Socket of the journald or syslog backend, defaults to `/run/systemd/journal/socket` or `/dev/log`
+    pub socket: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_size: 5 * 1024 * 1024,
+            max_files: 3,
+            format: LogFormat::Text,
+            backend: LogBackend::File,
+            socket: None,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn path(&self, runtime_dir: &Path) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| runtime_dir.join("dynisland.log"))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One object per line with `timestamp`, `level`, `target` and `message`
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum LogBackend {
+    /// The log file when the daemon is detached, stderr otherwise
+    File,
+    /// The native journald protocol, with the target in the `TARGET` field
+    Journald,
+    /// RFC 3164 messages on the local syslog socket
+    Syslog,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct GeneralConfig {
+    pub minimal_height: u32,
+    pub minimal_width: u32,
+    pub blur_radius: f64,
+    pub enable_drag_stretch: bool,
+    // pub hide_widget_timeout_ms: u32,
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            minimal_height: 40,
+            minimal_width: 60,
+            blur_radius: 6.0,
+            enable_drag_stretch: false, // whether to enable stretching widgets by dragging
+                                        // hide_widget_timeout_ms: 1000,
+                                        //TODO find a way to add scrolling label to settings
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let module_map = HashMap::<String, Value>::new();
+        let layout_map = HashMap::<String, Value>::new();
+        Self {
+            module_config: module_map,
+            layout_configs: layout_map,
+            layout: Some("FallbackLayout".to_string()),
+            general_style_config: GeneralConfig::default(),
+            loaded_modules: vec!["all".to_string()],
+            ipc: IpcConfig::default(),
+            log: LogConfig::default(),
+            show_config_errors: true,
+            debug: None,
+        }
+    }
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+        let res = options
+            .to_string_pretty(self, PrettyConfig::default())
+            .unwrap_or("unable to parse config".to_string());
+        write!(f, "{}", res)
+    }
+}
+
+impl Config {
+    pub fn get_runtime_dir(&self) -> PathBuf {
+        self.debug
+            .clone()
+            .map(|debug| PathBuf::from(debug.runtime_path))
+            .unwrap_or(get_default_runtime_path())
+    }
+}
+
+/// Config of a module or layout manager in the form their `update_config` takes
+pub fn config_string(name: &str, config: &Value) -> String {
+    let confs = ron::ser::to_string_pretty(config, PrettyConfig::default()).unwrap();
+    let mut confs = confs.replace("\\'", "\'");
+    if let Err(err) = json_strip_comments::strip(&mut confs) {
+        log::warn!("failed to strip trailing commas from {name} err: {err}");
+    };
+    confs
+}
+
+pub fn get_default_config_path() -> PathBuf {
+    glib::user_config_dir().join(CONFIG_REL_PATH)
+}
+fn get_default_runtime_path() -> PathBuf {
+    glib::user_runtime_dir().join(CONFIG_REL_PATH)
+}
+
+pub fn get_config(config_dir: &Path) -> Config {
+    try_get_config(config_dir).unwrap_or_else(|err| {
+        log::warn!("{err:#}, using default");
+        Config::default()
+    })
+}
+
+/// Like [`get_config`], but returns the error instead of falling back to the default config
+pub fn try_get_config(config_dir: &Path) -> Result<Config> {
+    read_config(config_dir).map(|(config, _)| config)
+}
+
+/// Replaces the content of a config file, the config watcher never sees half a file.
+///
+/// A symlink is written through: the file it points to is replaced and the link is kept
+pub fn write_config_file(path: &Path, content: &[u8]) -> Result<()> {
+    let target = match std::fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !path.is_symlink() => {
+            path.to_path_buf()
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to resolve {}", path.display()))
+        }
+    };
+    let tmp_path = PathBuf::from(format!("{}.tmp", target.display()));
+    std::fs::write(&tmp_path, content)
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, &target)
+        .with_context(|| format!("failed to write {}", target.display()))
+}
+
+/// Like [`try_get_config`], also returns the content of the file that was parsed
+pub fn read_config(config_dir: &Path) -> Result<(Config, String)> {
+    let config_path = config_dir.join("dynisland.ron");
+    let content = std::fs::read_to_string(&config_path)
+        .with_context(|| format!("failed to read {}", config_path.display()))?;
+    let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+    let config = options
+        .from_str(&content)
+        .with_context(|| format!("failed to parse {}", config_path.display()))?;
+    Ok((config, content))
+}
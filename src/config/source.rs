@@ -0,0 +1,310 @@
+//! Positions of the options in the text of a RON config, used to edit it without losing the comments
+//! and to point at an option in the diagnostics.
+//!
+//! Only the structure is scanned, strings, chars and comments are skipped and the values are not parsed,
+//! so the text should already be valid RON.
+
+use std::ops::Range;
+
+/// An entry of a struct, map, tuple or list, as byte ranges of the text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// `None` for the items of lists and tuples
+    pub key: Option<Range<usize>>,
+    pub value: Range<usize>,
+}
+
+/// A struct or tuple `(...)`, a map `{...}` or a list `[...]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Container {
+    pub open: usize,
+    /// Position of the closing bracket
+    pub close: usize,
+    pub entries: Vec<Entry>,
+}
+
+impl Container {
+    pub fn is_map(&self, text: &str) -> bool {
+        text.as_bytes()[self.open] == b'{'
+    }
+
+    /// The entry with `key`, or the item equal to `key` in a list, the quotes of strings are ignored
+    pub fn get<'a>(&'a self, text: &str, key: &str) -> Option<&'a Entry> {
+        self.entries.iter().find(|entry| {
+            let name = entry.key.as_ref().unwrap_or(&entry.value);
+            unquote(&text[name.clone()]) == key
+        })
+    }
+}
+
+fn unquote(token: &str) -> &str {
+    token
+        .strip_prefix('"')
+        .and_then(|token| token.strip_suffix('"'))
+        .unwrap_or(token)
+}
+
+/// The top level struct of the file, after the attributes like `#![enable(implicit_some)]`
+pub fn root(text: &str) -> Option<Container> {
+    let mut index = skip_trivia(text, 0);
+    while text[index..].starts_with("#!") {
+        let attribute = container_at(text, index + 2)?;
+        index = skip_trivia(text, attribute.close + 1);
+    }
+    container_at(text, index)
+}
+
+/// The entry at `path`, every key but the last one must be a struct or a map
+pub fn find(text: &str, path: &[&str]) -> Option<Entry> {
+    let (last, parents) = path.split_last()?;
+    let mut container = root(text)?;
+    for key in parents {
+        let entry = container.get(text, key)?;
+        container = container_at(text, entry.value.start)?;
+    }
+    container.get(text, last).cloned()
+}
+
+/// The struct, map or list at the value that starts at `start`,
+/// the name of a struct is skipped and `Some(...)` is the value inside it
+pub fn container_at(text: &str, start: usize) -> Option<Container> {
+    let bytes = text.as_bytes();
+    let mut index = skip_trivia(text, start);
+    let name_end = ident_end(text, index);
+    let name = &text[index..name_end];
+    index = skip_trivia(text, name_end);
+    let close = match bytes.get(index)? {
+        b'(' => b')',
+        b'[' => b']',
+        b'{' => b'}',
+        _ => return None,
+    };
+    let open = index;
+    let mut entries = Vec::new();
+    index += 1;
+    loop {
+        index = skip_trivia(text, index);
+        match bytes.get(index) {
+            None => return None,
+            Some(&byte) if byte == close => break,
+            Some(_) => {}
+        }
+        let first = index..value_end(text, index)?;
+        let after = skip_trivia(text, first.end);
+        let entry = if bytes.get(after) == Some(&b':') {
+            let value_start = skip_trivia(text, after + 1);
+            Entry {
+                key: Some(first),
+                value: value_start..value_end(text, value_start)?,
+            }
+        } else {
+            Entry {
+                key: None,
+                value: first,
+            }
+        };
+        index = skip_trivia(text, entry.value.end);
+        if bytes.get(index) == Some(&b',') {
+            index += 1;
+        }
+        entries.push(entry);
+    }
+    if name == "Some" {
+        if let [Entry { key: None, value }] = entries.as_slice() {
+            return container_at(text, value.start);
+        }
+    }
+    Some(Container {
+        open,
+        close: index,
+        entries,
+    })
+}
+
+/// End of the key or value that starts at `start`, before the `,`, `:` or closing bracket that ends it
+fn value_end(text: &str, start: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0usize;
+    let mut index = start;
+    let mut end = start;
+    while index < bytes.len() {
+        let next = skip_trivia(text, index);
+        if next != index {
+            index = next;
+            continue;
+        }
+        match bytes[index] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' if depth == 0 => return Some(end),
+            b')' | b']' | b'}' => depth -= 1,
+            b',' | b':' if depth == 0 => return Some(end),
+            b'"' | b'\'' => {
+                index = skip_literal(text, index)?;
+                end = index;
+                continue;
+            }
+            b'r' if raw_string_start(text, index) => {
+                index = skip_raw_string(text, index)?;
+                end = index;
+                continue;
+            }
+            _ => {}
+        }
+        index += text[index..].chars().next().map_or(1, char::len_utf8);
+        end = index;
+    }
+    None
+}
+
+/// Skips whitespace and comments
+pub fn skip_trivia(text: &str, start: usize) -> usize {
+    let bytes = text.as_bytes();
+    let mut index = start;
+    loop {
+        while bytes.get(index).is_some_and(u8::is_ascii_whitespace) {
+            index += 1;
+        }
+        if bytes[index..].starts_with(b"//") {
+            index = text[index..]
+                .find('\n')
+                .map_or(text.len(), |newline| index + newline);
+        } else if bytes[index..].starts_with(b"/*") {
+            // block comments can be nested
+            let mut depth = 0;
+            while index < bytes.len() {
+                if bytes[index..].starts_with(b"/*") {
+                    depth += 1;
+                    index += 2;
+                } else if bytes[index..].starts_with(b"*/") {
+                    depth -= 1;
+                    index += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    index += 1;
+                }
+            }
+        } else {
+            return index;
+        }
+    }
+}
+
+/// Skips a string or a char, `start` is the opening quote
+fn skip_literal(text: &str, start: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let quote = bytes[start];
+    let mut index = start + 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            byte if byte == quote => return Some(index + 1),
+            _ => index += 1,
+        }
+    }
+    None
+}
+
+/// `r"..."` or `r#"..."#`, not an identifier that starts with `r`
+fn raw_string_start(text: &str, start: usize) -> bool {
+    let after = text[start + 1..].trim_start_matches('#');
+    after.starts_with('"') && !text[..start].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+}
+
+fn skip_raw_string(text: &str, start: usize) -> Option<usize> {
+    let hashes = text[start + 1..].len() - text[start + 1..].trim_start_matches('#').len();
+    let content = start + 2 + hashes;
+    let terminator = format!("\"{}", "#".repeat(hashes));
+    text[content..]
+        .find(&terminator)
+        .map(|end| content + end + terminator.len())
+}
+
+fn ident_end(text: &str, start: usize) -> usize {
+    text[start..]
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .map_or(text.len(), |end| start + end)
+}
+
+/// Whitespace before `index` on its line, `None` if something else comes before it
+pub fn line_indent(text: &str, index: usize) -> Option<&str> {
+    let line_start = text[..index].rfind('\n').map_or(0, |newline| newline + 1);
+    let indent = &text[line_start..index];
+    indent.chars().all(char::is_whitespace).then_some(indent)
+}
+
+/// Whitespace at the start of the line of `index`
+pub fn leading_whitespace(text: &str, index: usize) -> &str {
+    let line_start = text[..index].rfind('\n').map_or(0, |newline| newline + 1);
+    let line = &text[line_start..];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"#![enable(implicit_some)]
+// a comment with loaded_modules: in it
+Config(
+    /* layout: "x", */ layout: "FallbackLayout",
+    loaded_modules: ["ClockModule", "music_module"],
+    module_config: {
+        "ClockModule": (format: "loaded_modules: %H:%M", tz: r#"a "quoted" )"#),
+        "music_module": Some((scrolling: true, sep: ',')),
+    },
+)
+"##;
+
+    fn value<'a>(path: &[&str]) -> &'a str {
+        let entry = find(CONFIG, path).unwrap();
+        &CONFIG[entry.value]
+    }
+
+    #[test]
+    fn finds_values_by_path() {
+        assert_eq!(value(&["layout"]), "\"FallbackLayout\"");
+        assert_eq!(
+            value(&["module_config", "ClockModule", "format"]),
+            "\"loaded_modules: %H:%M\""
+        );
+        assert_eq!(
+            value(&["module_config", "ClockModule", "tz"]),
+            r##"r#"a "quoted" )"#"##
+        );
+        assert_eq!(
+            value(&["module_config", "music_module", "scrolling"]),
+            "true"
+        );
+        assert_eq!(value(&["module_config", "music_module", "sep"]), "','");
+        assert_eq!(
+            value(&["loaded_modules", "music_module"]),
+            "\"music_module\""
+        );
+        assert!(find(CONFIG, &["module_config", "missing"]).is_none());
+    }
+
+    #[test]
+    fn keys_in_comments_and_strings_are_skipped() {
+        let entry = find(CONFIG, &["loaded_modules"]).unwrap();
+        let key = entry.key.unwrap();
+        assert_eq!(&CONFIG[key.clone()], "loaded_modules");
+        assert_eq!(line_indent(CONFIG, key.start), Some("    "));
+        assert_eq!(CONFIG[..key.start].matches('\n').count(), 4);
+    }
+
+    #[test]
+    fn containers() {
+        let root = root(CONFIG).unwrap();
+        assert!(!root.is_map(CONFIG));
+        assert_eq!(root.entries.len(), 3);
+        assert_eq!(&CONFIG[root.close..], ")\n");
+
+        let modules = find(CONFIG, &["module_config"]).unwrap();
+        let modules = container_at(CONFIG, modules.value.start).unwrap();
+        assert!(modules.is_map(CONFIG));
+        assert_eq!(modules.entries.len(), 2);
+        assert!(container_at("(a: 1", 0).is_none());
+    }
+}
@@ -0,0 +1,339 @@
+//! `dynisland config validate`, checks a config without starting the daemon.
+//!
+//! The main config is parsed like the daemon does, then the config of every module and layout manager
+//! is passed to `update_config` of a new instance that is never initialized.
+//! Creating a module runs its constructor, which can start its work (e.g. connect to a service),
+//! so that part can be skipped.
+//! The options that are not known by dynisland or by the module are reported as warnings.
+
+use std::{fmt::Display, path::Path};
+
+use abi_stable::std_types::{RResult, RString};
+use dynisland_core::{abi::abi_stable, ron};
+use ron::{extensions::Extensions, Value};
+use serde::Serialize;
+
+use super::{merge, source, Config, DebugConfig};
+use crate::{
+    app::App,
+    doctor::Severity,
+    layout_manager::{self, fallback_layout},
+    module_loading,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    /// 1-based, `None` if the position is unknown
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(
+        severity: Severity,
+        file: &Path,
+        position: Option<(usize, usize)>,
+        message: String,
+    ) -> Self {
+        Self {
+            severity,
+            file: file.display().to_string(),
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            message,
+        }
+    }
+}
+
+/// Like the compiler errors, `file:line:column: severity: message`
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, ":{line}:{column}")?;
+        }
+        write!(f, ": {}: {}", self.severity, self.message)
+    }
+}
+
+impl App {
+    /// Checks the config at `path`, the modules and layout managers are the ones in the config dir of the app.
+    ///
+    /// Their configs are only checked with `check_modules`, because they have to be created
+    pub fn validate_config(&self, path: &Path, check_modules: bool) -> Vec<Diagnostic> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => {
+                return vec![Diagnostic::new(
+                    Severity::Error,
+                    path,
+                    None,
+                    format!("failed to read the config: {err}"),
+                )]
+            }
+        };
+        let error = |position, message| Diagnostic::new(Severity::Error, path, position, message);
+        let warning =
+            |position, message| Diagnostic::new(Severity::Warning, path, position, message);
+
+        let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+        let mut diagnostics = Vec::new();
+        if let Err(err) = options.from_str::<Config>(&content) {
+            diagnostics.push(error(
+                Some((err.position.line, err.position.col)),
+                err.code.to_string(),
+            ));
+        }
+        // a syntax error, nothing else can be checked
+        let Ok(Value::Map(mut config)) = options.from_str::<Value>(&content) else {
+            return diagnostics;
+        };
+        let layout_configs = config.remove(&Value::String("layout_configs".to_string()));
+        let module_configs = config.remove(&Value::String("module_config".to_string()));
+
+        let mut unknown = Vec::new();
+        merge::missing_keys(
+            &Value::Map(config.clone()),
+            &known_options(),
+            "",
+            &mut unknown,
+        );
+        for key in unknown {
+            let position = locate(&content, &key.split('.').collect::<Vec<_>>());
+            diagnostics.push(warning(
+                position,
+                format!("unknown option `{key}`, it's ignored"),
+            ));
+        }
+
+        if !check_modules {
+            return diagnostics;
+        }
+        // the modules and layout managers create widgets
+        if let Err(err) = gtk::init() {
+            diagnostics.push(warning(
+                None,
+                format!("the configs of the modules and layout managers were not checked, failed to init gtk: {err}"),
+            ));
+            return diagnostics;
+        }
+
+        let loaded_modules: Vec<String> = config
+            .iter()
+            .find(|(key, _)| **key == Value::String("loaded_modules".to_string()))
+            .and_then(|(_, value)| value.clone().into_rust().ok())
+            .unwrap_or_else(|| vec!["all".to_string()]);
+        let module_defs = module_loading::get_module_definitions(&self.config_dir);
+        for name in &loaded_modules {
+            if name != "all" && !module_defs.contains_key(name) {
+                diagnostics.push(warning(
+                    locate(&content, &["loaded_modules", name]),
+                    format!("module `{name}` not found"),
+                ));
+            }
+        }
+        for (name, module_config) in entries(module_configs.as_ref()) {
+            let position = locate(&content, &["module_config", &name]);
+            let Some((constructor, _)) = module_defs.get(&name) else {
+                diagnostics.push(warning(
+                    position,
+                    format!("module `{name}` not found, its config is ignored"),
+                ));
+                continue;
+            };
+            if !loaded_modules.contains(&"all".to_string()) && !loaded_modules.contains(&name) {
+                diagnostics.push(warning(
+                    position,
+                    format!("module `{name}` is not in loaded_modules, its config is ignored"),
+                ));
+            }
+            let mut module = match constructor(self.app_send.clone().unwrap()) {
+                RResult::ROk(module) => module,
+                RResult::RErr(err) => {
+                    diagnostics.push(warning(
+                        position,
+                        format!(
+                            "failed to create module `{name}`, its config was not checked: {err}"
+                        ),
+                    ));
+                    continue;
+                }
+            };
+            diagnostics.extend(check_entry(
+                &content,
+                path,
+                &["module_config", &name],
+                module_config,
+                module.default_config(),
+                module.update_config(super::config_string(&name, module_config).into()),
+            ));
+        }
+
+        let mut layout_defs = module_loading::get_lm_definitions(&self.config_dir);
+        layout_defs.insert(layout_manager::NAME.to_string(), fallback_layout::new);
+        for (name, layout_config) in entries(layout_configs.as_ref()) {
+            let position = locate(&content, &["layout_configs", &name]);
+            let Some(constructor) = layout_defs.get(&name) else {
+                diagnostics.push(warning(
+                    position,
+                    format!("layout manager `{name}` not found, its config is ignored"),
+                ));
+                continue;
+            };
+            let mut layout = match constructor(self.application.clone().into()) {
+                RResult::ROk(layout) => layout,
+                RResult::RErr(err) => {
+                    diagnostics.push(warning(
+                        position,
+                        format!("failed to create layout manager `{name}`, its config was not checked: {err}"),
+                    ));
+                    continue;
+                }
+            };
+            diagnostics.extend(check_entry(
+                &content,
+                path,
+                &["layout_configs", &name],
+                layout_config,
+                layout.default_config(),
+                layout.update_config(super::config_string(&name, layout_config).into()),
+            ));
+        }
+        diagnostics
+    }
+}
+
+/// The result of `update_config` and the options that are not in the default config of a module or layout manager
+fn check_entry<E: Display>(
+    content: &str,
+    path: &Path,
+    key_path: &[&str],
+    config: &Value,
+    default_config: RResult<RString, E>,
+    update_result: RResult<(), E>,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let name = key_path[key_path.len() - 1];
+    if let RResult::RErr(err) = update_result {
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            path,
+            locate(content, key_path),
+            format!("invalid config for `{name}`: {err}"),
+        ));
+    }
+    let default_config = match default_config {
+        RResult::ROk(default_config) => ron::from_str::<Value>(&default_config).ok(),
+        RResult::RErr(_) => None,
+    };
+    if let Some(default_config) = default_config {
+        let mut unknown = Vec::new();
+        merge::missing_keys(config, &default_config, "", &mut unknown);
+        for key in unknown {
+            let mut option_path = key_path.to_vec();
+            option_path.extend(key.split('.'));
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                path,
+                locate(content, &option_path),
+                format!("unknown option `{key}` for `{name}`, it's ignored"),
+            ));
+        }
+    }
+    diagnostics
+}
+
+/// Every option of the main config, except the configs of the modules and layout managers
+fn known_options() -> Value {
+    let config = Config {
+        debug: Some(DebugConfig::default()),
+        ..Default::default()
+    };
+    let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+    options
+        .to_string(&config)
+        .ok()
+        .and_then(|config| options.from_str(&config).ok())
+        .unwrap_or(Value::Unit)
+}
+
+/// Entries of `layout_configs` or `module_config`, sorted by name
+fn entries(configs: Option<&Value>) -> Vec<(String, &Value)> {
+    let Some(Value::Map(configs)) = configs else {
+        return Vec::new();
+    };
+    configs
+        .iter()
+        .filter_map(|(name, config)| match name {
+            Value::String(name) => Some((name.clone(), config)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Position of an option (or of an item of a list) in the config, as 1-based line and column
+fn locate(content: &str, key_path: &[&str]) -> Option<(usize, usize)> {
+    let entry = source::find(content, key_path)?;
+    let offset = entry.key.unwrap_or(entry.value).start;
+    let before = &content[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    Some((line, column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"Config(
+    // layout: "x",
+    layout: "FallbackLayout",
+    loaded_modules: ["music_module", "ClockModule"],
+    module_config: {
+        "ClockModule": (format: "module_config: %H", scrolling: false),
+        "music_module": (
+            scrolling: true,
+        ),
+    },
+)
+"#;
+
+    #[test]
+    fn locate_options() {
+        assert_eq!(locate(CONFIG, &["layout"]), Some((3, 5)));
+        assert_eq!(
+            locate(CONFIG, &["loaded_modules", "ClockModule"]),
+            Some((4, 38))
+        );
+        assert_eq!(
+            locate(CONFIG, &["module_config", "ClockModule"]),
+            Some((6, 9))
+        );
+        assert_eq!(
+            locate(CONFIG, &["module_config", "ClockModule", "scrolling"]),
+            Some((6, 54))
+        );
+        assert_eq!(
+            locate(CONFIG, &["module_config", "music_module", "scrolling"]),
+            Some((8, 13))
+        );
+        assert_eq!(locate(CONFIG, &["module_config", "missing"]), None);
+    }
+
+    #[test]
+    fn locate_skips_keys_in_strings_and_comments() {
+        // `module_config:` is in a comment and in a string before the real keys
+        let config = "Config(\n    // module_config: {}\n    layout: \"module_config: x\",\n    module_config: {},\n)";
+        assert_eq!(locate(config, &["module_config"]), Some((4, 5)));
+        assert_eq!(locate(config, &["layout"]), Some((3, 5)));
+    }
+}
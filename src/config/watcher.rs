@@ -0,0 +1,188 @@
+//! Watches the config files and asks for a reload when they change.
+//!
+//! The directories of the files are watched instead of the files, so editors that save by writing
+//! a new file and renaming it over the old one are seen too.
+//! A file that is a symlink is watched both where the link is and where it points to,
+//! and the files imported by `dynisland.scss` are watched as well.
+//! The events are debounced, the reload starts when the files didn't change for [`DEBOUNCE`].
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
+
+use dynisland_core::abi::log;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    app::{App, BackendServerCommand},
+    ipc::{protocol::RequestId, INTERNAL_REQUEST_ID},
+};
+
+/// Time without changes before a reload, saving a file is usually more than one event
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watcher of the config files, the clones share it
+#[derive(Clone)]
+pub struct ConfigWatcher {
+    watcher: Arc<Mutex<RecommendedWatcher>>,
+    /// Directories in `watcher`
+    dirs: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Files that trigger a reload, as they appear in the events
+    files: Arc<RwLock<HashSet<PathBuf>>>,
+}
+
+impl ConfigWatcher {
+    pub fn new(
+        server_send: UnboundedSender<(RequestId, BackendServerCommand)>,
+    ) -> notify::Result<Self> {
+        log::info!("starting config watcher");
+        let files: Arc<RwLock<HashSet<PathBuf>>> = Arc::default();
+        let (change_send, change_recv) = mpsc::channel::<()>();
+
+        let watched_files = files.clone();
+        // the lock of the watcher can't be taken here, `watch` waits for the thread that runs this
+        let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let event = match res {
+                Ok(event) => event,
+                Err(err) => {
+                    log::error!("Notify watcher error: {err}");
+                    return;
+                }
+            };
+            if matches!(event.kind, notify::EventKind::Access(_)) {
+                return;
+            }
+            let files = watched_files.read().unwrap();
+            if event.paths.iter().any(|path| files.contains(path)) {
+                log::trace!("config event: {event:?}");
+                let _ = change_send.send(());
+            }
+        })?;
+
+        thread::Builder::new()
+            .name("config-watcher".to_string())
+            .spawn(move || {
+                while change_recv.recv().is_ok() {
+                    loop {
+                        match change_recv.recv_timeout(DEBOUNCE) {
+                            Ok(()) => continue,
+                            Err(mpsc::RecvTimeoutError::Timeout) => break,
+                            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                        }
+                    }
+                    log::debug!("Config change detected");
+                    if server_send
+                        .send((INTERNAL_REQUEST_ID, BackendServerCommand::ReloadConfig))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            })
+            .expect("failed to spawn the config watcher thread");
+
+        Ok(Self {
+            watcher: Arc::new(Mutex::new(watcher)),
+            dirs: Arc::default(),
+            files,
+        })
+    }
+
+    /// Replaces the watched files, the ones that don't exist yet are seen when they are created
+    pub fn watch(&self, paths: &[PathBuf]) {
+        let mut files = HashSet::new();
+        for path in paths {
+            files.insert(path.clone());
+            // a symlink can be replaced or its target edited
+            if let Ok(target) = path.canonicalize() {
+                files.insert(target);
+            }
+        }
+        let dirs: HashSet<PathBuf> = files
+            .iter()
+            .filter_map(|file| file.parent().map(Path::to_path_buf))
+            .collect();
+        *self.files.write().unwrap() = files;
+
+        let mut watcher = self.watcher.lock().unwrap();
+        let mut watched = self.dirs.lock().unwrap();
+        for dir in watched.difference(&dirs) {
+            let _ = watcher.unwatch(dir);
+        }
+        watched.retain(|dir| dirs.contains(dir));
+        for dir in dirs {
+            if watched.contains(&dir) {
+                continue;
+            }
+            match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    log::debug!("watching {}", dir.display());
+                    watched.insert(dir);
+                }
+                Err(err) => log::warn!(
+                    "failed to watch {}, its config files are not reloaded automatically: {err}",
+                    dir.display()
+                ),
+            }
+        }
+    }
+}
+
+/// The file system of grass that remembers the files it read, they are the ones imported by the stylesheet
+#[derive(Debug, Default)]
+pub struct RecordingFs {
+    pub read: RefCell<Vec<PathBuf>>,
+    /// The files that were read successfully, with their content
+    pub content: RefCell<Vec<(PathBuf, Vec<u8>)>>,
+}
+
+impl grass::Fs for RecordingFs {
+    fn is_dir(&self, path: &Path) -> bool {
+        grass::StdFs.is_dir(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        grass::StdFs.is_file(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.read.borrow_mut().push(path.to_path_buf());
+        let content = grass::StdFs.read(path)?;
+        self.content
+            .borrow_mut()
+            .push((path.to_path_buf(), content.clone()));
+        Ok(content)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        grass::StdFs.canonicalize(path)
+    }
+}
+
+impl App {
+    /// Watches `dynisland.ron`, `dynisland.scss` and the files imported by the stylesheet
+    pub(crate) fn watch_config_files(&self, config_dir: &Path) {
+        let Some(watcher) = &self.config_watcher else {
+            return;
+        };
+        let mut files = vec![
+            config_dir.join("dynisland.ron"),
+            config_dir.join("dynisland.scss"),
+        ];
+        for file in &self.stylesheet_files {
+            if let Ok(file) = std::path::absolute(file) {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        watcher.watch(&files);
+    }
+}
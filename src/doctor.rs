@@ -0,0 +1,374 @@
+//! Environment diagnostics for `dynisland doctor`, they don't need the daemon.
+//!
+//! Every check has a [`Severity`], warnings and errors also have an actionable fix.
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use abi_stable::{type_layout::TypeLayout, StableAbi};
+use dynisland_core::abi::{
+    abi_stable, gtk_layer_shell, layout::LayoutManagerBuilderRef, module::ModuleBuilderRef,
+};
+use gtk::prelude::*;
+use serde::Serialize;
+
+use crate::{config, module_loading, pid_file};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Ok,
+    /// dynisland works, but probably not as expected
+    Warning,
+    /// dynisland won't start or will ignore part of the config
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub severity: Severity,
+    pub message: String,
+    /// What to do about it, `None` if the check passed
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, message: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            severity: Severity::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(name: &str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            severity: Severity::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn error(name: &str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            severity: Severity::Error,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}: {}", self.severity, self.name, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n    fix: {fix}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs every check, in the order they should be read
+pub fn run(config_dir: &Path, runtime_dir: &Path) -> Vec<Check> {
+    let mut checks = display_checks();
+    checks.push(nvidia_check());
+    checks.extend(config_checks(config_dir));
+    checks.extend(library_checks(config_dir));
+    checks.extend(runtime_checks(runtime_dir));
+    checks
+}
+
+/// Whether the proprietary NVIDIA driver is loaded
+pub fn is_nvidia() -> bool {
+    Path::new("/proc/driver/nvidia/version").exists() || Path::new("/sys/module/nvidia").exists()
+}
+
+fn display_checks() -> Vec<Check> {
+    if let Err(err) = gtk::init() {
+        return vec![Check::error(
+            "display",
+            format!("failed to init gtk: {err}"),
+            "run it from the graphical session, WAYLAND_DISPLAY must be set",
+        )];
+    }
+    let mut checks = Vec::new();
+    if gtk_layer_shell::is_supported() {
+        checks.push(Check::ok("layer shell", "wlr-layer-shell is supported"));
+    } else {
+        checks.push(Check::warning(
+            "layer shell",
+            "the compositor doesn't support wlr-layer-shell, the windows will be normal windows",
+            "use a compositor that supports it (e.g. Hyprland, Sway, niri, KDE), or set `layer_shell: false` in the layout config to silence this",
+        ));
+    }
+
+    // the renderer is picked when the first window is realized
+    let window = gtk::Window::new();
+    WidgetExt::realize(&window);
+    let renderer = window
+        .renderer()
+        .map(|renderer| renderer.type_().name().to_string());
+    window.destroy();
+    let source = match std::env::var("GSK_RENDERER") {
+        Ok(value) => format!("GSK_RENDERER={value}"),
+        Err(_) => "picked by gtk".to_string(),
+    };
+    checks.push(match renderer.as_deref() {
+        Some("GskCairoRenderer") => Check::warning(
+            "renderer",
+            format!("GskCairoRenderer ({source}), everything is rendered on the CPU"),
+            "check the GPU drivers, or set GSK_RENDERER=gl",
+        ),
+        Some(renderer) => Check::ok("renderer", format!("{renderer} ({source})")),
+        None => Check::error(
+            "renderer",
+            format!("no renderer could be created ({source})"),
+            "unset GSK_RENDERER or set it to one of gl, ngl, vulkan or cairo",
+        ),
+    });
+    checks
+}
+
+fn nvidia_check() -> Check {
+    if !is_nvidia() {
+        return Check::ok("nvidia", "no NVIDIA driver loaded");
+    }
+    match std::env::var("GSK_RENDERER") {
+        Ok(value) => Check::ok(
+            "nvidia",
+            format!("NVIDIA driver loaded, GSK_RENDERER={value}"),
+        ),
+        Err(_) => Check::warning(
+            "nvidia",
+            "NVIDIA driver loaded, with the default renderer dynisland can use a lot of memory",
+            "set GSK_RENDERER=vulkan (or GSK_RENDERER=gl) in the environment of the daemon",
+        ),
+    }
+}
+
+fn config_checks(config_dir: &Path) -> Vec<Check> {
+    if !config_dir.is_dir() {
+        return vec![Check::error(
+            "config dir",
+            format!("{} doesn't exist", config_dir.display()),
+            format!(
+                "mkdir -p {0} && dynisland default-config > {0}/dynisland.ron",
+                config_dir.display()
+            ),
+        )];
+    }
+    let mut checks = vec![Check::ok("config dir", config_dir.display().to_string())];
+    for (name, dir) in [
+        ("modules dir", module_loading::module_dir(config_dir)),
+        ("layouts dir", module_loading::layout_dir(config_dir)),
+    ] {
+        checks.push(if dir.is_dir() {
+            Check::ok(name, dir.display().to_string())
+        } else {
+            Check::warning(
+                name,
+                format!(
+                    "{} doesn't exist, only the embedded ones can be used",
+                    dir.display()
+                ),
+                format!("mkdir -p {}", dir.display()),
+            )
+        });
+    }
+
+    let config_path = config_dir.join("dynisland.ron");
+    checks.push(if !config_path.exists() {
+        Check::warning(
+            "dynisland.ron",
+            format!(
+                "{} doesn't exist, the default config is used",
+                config_path.display()
+            ),
+            format!("dynisland default-config > {}", config_path.display()),
+        )
+    } else {
+        match config::try_get_config(config_dir) {
+            Ok(_) => Check::ok("dynisland.ron", "parsed"),
+            Err(err) => Check::error(
+                "dynisland.ron",
+                format!("{err:#}, the default config is used"),
+                "fix the error, `dynisland default-config` prints a valid config",
+            ),
+        }
+    });
+
+    let style_path = config_dir.join("dynisland.scss");
+    checks.push(if !style_path.exists() {
+        Check::warning(
+            "dynisland.scss",
+            format!(
+                "{} doesn't exist, only the default style is used",
+                style_path.display()
+            ),
+            format!("touch {}", style_path.display()),
+        )
+    } else {
+        match grass::from_path(&style_path, &grass::Options::default()) {
+            Ok(_) => Check::ok("dynisland.scss", "parsed"),
+            Err(err) => Check::error(
+                "dynisland.scss",
+                format!("{err}, the stylesheet is not loaded"),
+                "fix the error in the stylesheet",
+            ),
+        }
+    });
+    checks
+}
+
+/// Checks the ABI of the `.so` files, they are loaded (so their static constructors run)
+/// but the modules and layout managers in them are not created
+fn library_checks(config_dir: &Path) -> Vec<Check> {
+    let kinds: [(&str, &str, PathBuf, &'static TypeLayout); 2] = [
+        (
+            "module",
+            "module",
+            module_loading::module_dir(config_dir),
+            ModuleBuilderRef::LAYOUT,
+        ),
+        (
+            "layout manager",
+            "layoutmanager",
+            module_loading::layout_dir(config_dir),
+            LayoutManagerBuilderRef::LAYOUT,
+        ),
+    ];
+    let mut checks = Vec::new();
+    // in debug builds modules and layout managers are in the same directory
+    let mut ignored = HashSet::new();
+    for (kind, suffix, dir, interface) in kinds {
+        let Ok(files) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut paths: Vec<PathBuf> = files
+            .filter_map(|file| file.ok().map(|file| file.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        for path in paths {
+            let file_name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let Some(name) = file_name
+                .to_lowercase()
+                .strip_suffix(".so")
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            if !name.ends_with("module") && !name.ends_with("layoutmanager") {
+                if ignored.insert(path.clone()) {
+                    checks.push(Check::warning(
+                        &file_name,
+                        "ignored, the file name must end with module.so or layoutmanager.so",
+                        "rename it, or remove it if it's not a dynisland library",
+                    ));
+                }
+                continue;
+            }
+            if !name.ends_with(suffix) {
+                continue;
+            }
+            let check = match module_loading::compatible_header(&path, interface) {
+                Ok(_) => Check::ok(&file_name, format!("compatible {kind}")),
+                Err(err) => {
+                    // the full error lists every incompatible type
+                    let err = err.to_string();
+                    let reason = err.lines().next().unwrap_or_default();
+                    Check::error(
+                        &file_name,
+                        format!("incompatible {kind}, it won't be loaded: {reason}"),
+                        format!(
+                            "rebuild it against the dynisland-abi used by dynisland {}, or remove {}",
+                            env!("CARGO_PKG_VERSION"),
+                            path.display()
+                        ),
+                    )
+                }
+            };
+            checks.push(check);
+        }
+    }
+    checks
+}
+
+/// Leftovers of a daemon that didn't exit cleanly
+fn runtime_checks(runtime_dir: &Path) -> Vec<Check> {
+    let socket_path = runtime_dir.join("dynisland.sock");
+    let pid_path = runtime_dir.join(pid_file::PID_FILE_NAME);
+    if pid_file::is_locked(runtime_dir) {
+        let pid =
+            pid_file::read_pid(runtime_dir).map_or("unknown".to_string(), |pid| pid.to_string());
+        if socket_path.exists() {
+            return vec![Check::ok("daemon", format!("running (pid {pid})"))];
+        }
+        return vec![Check::warning(
+            "daemon",
+            format!(
+                "running (pid {pid}) but {} doesn't exist, the commands can't reach it",
+                socket_path.display()
+            ),
+            "dynisland restart",
+        )];
+    }
+
+    let mut checks = Vec::new();
+    if socket_path.exists() {
+        match UnixStream::connect(&socket_path) {
+            // a daemon older than the PID file
+            Ok(_) => checks.push(Check::ok("daemon", "running")),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                checks.push(Check::warning(
+                    "socket",
+                    format!(
+                        "{} is stale, no daemon is listening on it",
+                        socket_path.display()
+                    ),
+                    format!("rm {}", socket_path.display()),
+                ))
+            }
+            Err(err) => checks.push(Check::warning(
+                "socket",
+                format!("can't connect to {}: {err}", socket_path.display()),
+                format!("check the permissions of {}", runtime_dir.display()),
+            )),
+        }
+    }
+    if pid_path.exists() {
+        checks.push(Check::warning(
+            "pid file",
+            format!("{} is stale, no daemon holds its lock", pid_path.display()),
+            format!("rm {}", pid_path.display()),
+        ));
+    }
+    if checks.is_empty() {
+        checks.push(Check::ok("daemon", "not running, no stale files"));
+    }
+    checks
+}
@@ -0,0 +1,153 @@
+//! Built-in activity that shows the errors of the config, the stylesheet and the module configs.
+//!
+//! It's registered like the activities of the modules, with [`UIServerCommand::AddActivity`],
+//! when a reload has errors and removed by the first reload without them.
+//! The minimal and compact modes are a badge, the expanded mode has the list of errors.
+
+use dynisland_core::{
+    abi::{gdk, gtk, log, module::ActivityIdentifier, module::UIServerCommand},
+    graphics::activity_widget::{boxed_activity_mode::ActivityMode, ActivityWidget},
+};
+use gtk::{prelude::*, GestureClick};
+
+use crate::app::App;
+
+/// Name of the module of the activity, no module can have it
+pub const MODULE_NAME: &str = "dynisland";
+const ACTIVITY_NAME: &str = "config-errors";
+
+pub struct ErrorActivity {
+    id: ActivityIdentifier,
+    widget: ActivityWidget,
+    summary: gtk::Label,
+    error_list: gtk::Box,
+    registered: bool,
+}
+
+impl ErrorActivity {
+    fn new() -> Self {
+        let widget = ActivityWidget::new(&format!("{ACTIVITY_NAME}-{MODULE_NAME}"));
+        widget.add_css_class(ACTIVITY_NAME);
+
+        let minimal = gtk::Label::new(Some("!"));
+        minimal.add_css_class("error-badge");
+        minimal.set_width_request(30);
+        minimal.set_height_request(30);
+        minimal.set_valign(gtk::Align::Center);
+        minimal.set_halign(gtk::Align::Center);
+
+        let compact = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        compact.set_height_request(40);
+        compact.set_valign(gtk::Align::Center);
+        compact.set_halign(gtk::Align::Center);
+        let badge = gtk::Label::new(Some("!"));
+        badge.add_css_class("error-badge");
+        let summary = gtk::Label::new(None);
+        summary.add_css_class("error-summary");
+        compact.append(&badge);
+        compact.append(&summary);
+
+        let expanded = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        expanded.set_width_request(400);
+        expanded.set_valign(gtk::Align::Center);
+        expanded.set_halign(gtk::Align::Center);
+        let title = gtk::Label::new(Some("dynisland config errors"));
+        title.add_css_class("error-title");
+        let error_list = gtk::Box::new(gtk::Orientation::Vertical, 4);
+        expanded.append(&title);
+        expanded.append(&error_list);
+
+        widget.set_minimal_mode_widget(minimal);
+        widget.set_compact_mode_widget(compact);
+        widget.set_expanded_mode_widget(expanded);
+        register_mode_gestures(&widget);
+
+        Self {
+            id: ActivityIdentifier::new(MODULE_NAME, ACTIVITY_NAME),
+            widget,
+            summary,
+            error_list,
+            registered: false,
+        }
+    }
+
+    fn set_errors(&self, errors: &[String]) {
+        let summary = match errors.len() {
+            1 => "1 config error".to_string(),
+            count => format!("{count} config errors"),
+        };
+        self.summary.set_label(&summary);
+        while let Some(child) = self.error_list.first_child() {
+            self.error_list.remove(&child);
+        }
+        for error in errors {
+            let label = gtk::Label::new(Some(error));
+            label.add_css_class("error");
+            label.set_wrap(true);
+            label.set_xalign(0.0);
+            self.error_list.append(&label);
+        }
+    }
+}
+
+impl App {
+    /// Shows `errors` in the error activity, it's removed if there are none
+    pub(crate) fn show_config_errors(&mut self, errors: &[String]) {
+        let Some(app_send) = self.app_send.clone() else {
+            return;
+        };
+        if errors.is_empty() || !self.config.show_config_errors {
+            if let Some(activity) = self
+                .error_activity
+                .as_mut()
+                .filter(|activity| activity.registered)
+            {
+                let _ = app_send.send(UIServerCommand::RemoveActivity {
+                    activity_id: activity.id.clone(),
+                });
+                activity.registered = false;
+            }
+            return;
+        }
+        let activity = self.error_activity.get_or_insert_with(ErrorActivity::new);
+        activity.set_errors(errors);
+        if activity.registered {
+            return;
+        }
+        activity.widget.set_mode(ActivityMode::Compact);
+        let command = UIServerCommand::AddActivity {
+            activity_id: activity.id.clone(),
+            widget: activity.widget.clone().upcast::<gtk::Widget>().into(),
+        };
+        match app_send.send(command) {
+            Ok(()) => activity.registered = true,
+            Err(err) => log::warn!("failed to show the config errors: {err}"),
+        }
+    }
+}
+
+/// Primary click expands the activity, secondary click goes back to the smaller mode
+fn register_mode_gestures(activity_widget: &ActivityWidget) {
+    for button in [gdk::BUTTON_PRIMARY, gdk::BUTTON_SECONDARY] {
+        let gesture = GestureClick::new();
+        gesture.set_button(button);
+        gesture.connect_released(move |gest, _, x, y| {
+            let aw = gest.widget().downcast::<ActivityWidget>().unwrap();
+            if x < 0.0
+                || y < 0.0
+                || x > aw.size(gtk::Orientation::Horizontal).into()
+                || y > aw.size(gtk::Orientation::Vertical).into()
+            {
+                return;
+            }
+            let mode = match (button, aw.mode()) {
+                (gdk::BUTTON_PRIMARY, ActivityMode::Compact) => ActivityMode::Expanded,
+                (gdk::BUTTON_SECONDARY, ActivityMode::Expanded) => ActivityMode::Compact,
+                (gdk::BUTTON_SECONDARY, ActivityMode::Compact) => ActivityMode::Minimal,
+                _ => return,
+            };
+            aw.set_mode(mode);
+        });
+        activity_widget.add_controller(gesture);
+    }
+}
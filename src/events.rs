@@ -0,0 +1,130 @@
+//! Events broadcast by the daemon, they are streamed as newline-delimited JSON
+//! to the clients that run `dynisland subscribe`.
+
+use bincode::{Decode, Encode};
+use dynisland_core::{
+    abi::module::ActivityIdentifier, graphics::activity_widget::boxed_activity_mode::ActivityMode,
+};
+use serde::Serialize;
+
+pub type EventSender = tokio::sync::broadcast::Sender<Event>;
+
+/// Number of events a slow subscriber can fall behind before it starts losing them
+pub const EVENT_CHANNEL_CAPACITY: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ActivityAdded {
+        module: String,
+        activity: String,
+    },
+    ActivityRemoved {
+        module: String,
+        activity: String,
+    },
+    ModeChanged {
+        module: String,
+        activity: String,
+        mode: String,
+    },
+    NotificationRequested {
+        module: String,
+        activity: String,
+        mode: String,
+        duration: Option<u64>,
+    },
+    ConfigReloaded,
+    ModuleLoadFailed {
+        module: String,
+        error: String,
+    },
+}
+
+impl Event {
+    pub fn activity_added(id: &ActivityIdentifier) -> Self {
+        Self::ActivityAdded {
+            module: id.module().to_string(),
+            activity: id.activity().to_string(),
+        }
+    }
+
+    pub fn activity_removed(id: &ActivityIdentifier) -> Self {
+        Self::ActivityRemoved {
+            module: id.module().to_string(),
+            activity: id.activity().to_string(),
+        }
+    }
+
+    pub fn mode_changed(id: &ActivityIdentifier, mode: ActivityMode) -> Self {
+        Self::ModeChanged {
+            module: id.module().to_string(),
+            activity: id.activity().to_string(),
+            mode: mode.to_string(),
+        }
+    }
+
+    pub fn notification_requested(
+        id: &ActivityIdentifier,
+        mode: ActivityMode,
+        duration: Option<u64>,
+    ) -> Self {
+        Self::NotificationRequested {
+            module: id.module().to_string(),
+            activity: id.activity().to_string(),
+            mode: mode.to_string(),
+            duration,
+        }
+    }
+
+    pub fn module(&self) -> Option<&str> {
+        match self {
+            Event::ActivityAdded { module, .. }
+            | Event::ActivityRemoved { module, .. }
+            | Event::ModeChanged { module, .. }
+            | Event::NotificationRequested { module, .. }
+            | Event::ModuleLoadFailed { module, .. } => Some(module),
+            Event::ConfigReloaded => None,
+        }
+    }
+
+    /// Activity identifier in the same format used by the cli (`activity@module`)
+    pub fn activity_identifier(&self) -> Option<String> {
+        match self {
+            Event::ActivityAdded { module, activity }
+            | Event::ActivityRemoved { module, activity }
+            | Event::ModeChanged {
+                module, activity, ..
+            }
+            | Event::NotificationRequested {
+                module, activity, ..
+            } => Some(format!("{activity}@{module}")),
+            Event::ConfigReloaded | Event::ModuleLoadFailed { .. } => None,
+        }
+    }
+}
+
+/// Selects which events are sent to a subscriber.
+///
+/// An empty filter matches everything, events that are not related to a module
+/// (like [`Event::ConfigReloaded`]) are always sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct EventFilter {
+    pub modules: Vec<String>,
+    pub activities: Vec<String>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        if self.modules.is_empty() && self.activities.is_empty() {
+            return true;
+        }
+        let Some(module) = event.module() else {
+            return true;
+        };
+        self.modules.iter().any(|filter| filter == module)
+            || event
+                .activity_identifier()
+                .is_some_and(|id| self.activities.contains(&id))
+    }
+}
@@ -0,0 +1,87 @@
+//! Named instances, they make it possible to run multiple independent daemons at the same time.
+//!
+//! The default instance uses the same paths and names as before,
+//! a named instance gets its own runtime directory (and so its own socket and log file),
+//! GApplication id and D-Bus name.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use crate::{config::Config, ipc::dbus};
+
+pub const APPLICATION_ID: &str = "com.github.cr3eperall.dynisland";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Instance {
+    name: Option<String>,
+}
+
+impl Instance {
+    /// Only ascii letters, digits and `_` are allowed and the name can't start with a digit,
+    /// it's used as it is in paths, in the GApplication id and in the D-Bus name
+    pub fn new(name: Option<String>) -> Result<Self> {
+        if let Some(name) = &name {
+            if name.is_empty()
+                || name.starts_with(|c: char| c.is_ascii_digit())
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                bail!(
+                    "invalid instance name {name:?}, only letters, digits and '_' are allowed and it can't start with a digit"
+                );
+            }
+        }
+        Ok(Self { name })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Directory for the socket and log file, named instances are in `instances/<name>`
+    pub fn runtime_dir(&self, config: &Config) -> PathBuf {
+        let runtime_dir = config.get_runtime_dir();
+        match &self.name {
+            Some(name) => runtime_dir.join("instances").join(name),
+            None => runtime_dir,
+        }
+    }
+
+    pub fn application_id(&self) -> String {
+        self.qualify(APPLICATION_ID)
+    }
+
+    pub fn bus_name(&self) -> String {
+        self.qualify(dbus::BUS_NAME)
+    }
+
+    /// Appends the instance name as the last element of a GApplication id or D-Bus name
+    fn qualify(&self, base: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{base}.{name}"),
+            None => base.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_valid_id_elements() {
+        let instance = Instance::new(Some("test_2".to_string())).unwrap();
+        assert_eq!(
+            instance.application_id(),
+            "com.github.cr3eperall.dynisland.test_2"
+        );
+        assert_eq!(instance.bus_name(), format!("{}.test_2", dbus::BUS_NAME));
+        for name in ["", "a-b", "1", "a.b", "a/b", "è"] {
+            assert!(Instance::new(Some(name.to_string())).is_err(), "{name:?}");
+        }
+        assert_eq!(
+            Instance::new(None).unwrap().application_id(),
+            APPLICATION_ID
+        );
+    }
+}
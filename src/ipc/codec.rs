@@ -0,0 +1,296 @@
+//! Length-prefixed framing used for the requests and the responses on the IPC socket.
+//!
+//! A frame is a big-endian `u32` length followed by that many bytes of bincode.
+//! The length is checked against [`FrameCodec::max_frame_size`] before anything is allocated
+//! and a frame is only accepted if the payload decodes using every byte of it.
+//!
+//! [`FrameCodec::read_blocking`] works on any reader, so it can be fed arbitrary bytes from a slice.
+
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use bincode::{
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const LENGTH_PREFIX_LEN: usize = 4;
+
+/// Upper bound for [`FrameCodec::max_frame_size`], it's also the allocation limit of the decoder,
+/// so a small frame can't claim a huge collection
+pub const MAX_FRAME_SIZE_LIMIT: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(800);
+
+fn bincode_config() -> impl bincode::config::Config {
+    bincode::config::standard().with_limit::<MAX_FRAME_SIZE_LIMIT>()
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The peer closed the connection before sending a frame
+    Closed,
+    TooLarge {
+        len: usize,
+        max: usize,
+    },
+    Encode(EncodeError),
+    Decode(DecodeError),
+    /// The payload was decoded without using every byte of the frame
+    TrailingBytes {
+        len: usize,
+        used: usize,
+    },
+    Timeout,
+    Io(io::Error),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "connection closed before a frame was received"),
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {len} bytes is larger than the maximum ({max})")
+            }
+            FrameError::Encode(err) => write!(f, "failed to encode frame: {err}"),
+            FrameError::Decode(err) => write!(f, "malformed frame: {err}"),
+            FrameError::TrailingBytes { len, used } => {
+                write!(f, "malformed frame: only {used} of {len} bytes were used")
+            }
+            FrameError::Timeout => write!(f, "timed out while reading a frame"),
+            FrameError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => FrameError::Timeout,
+            _ => FrameError::Io(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+    /// Maximum payload length, without the length prefix
+    pub max_frame_size: usize,
+    /// How long a frame can take to arrive once the peer is expected to send one
+    pub read_timeout: Duration,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        }
+    }
+}
+
+impl FrameCodec {
+    /// `max_frame_size` is capped at [`MAX_FRAME_SIZE_LIMIT`]
+    pub fn new(max_frame_size: usize, read_timeout: Duration) -> Self {
+        Self {
+            max_frame_size: max_frame_size.min(MAX_FRAME_SIZE_LIMIT),
+            read_timeout,
+        }
+    }
+
+    pub fn encode<T: Encode>(&self, value: &T) -> Result<Vec<u8>, FrameError> {
+        let payload =
+            bincode::encode_to_vec(value, bincode_config()).map_err(FrameError::Encode)?;
+        self.check_len(payload.len())?;
+        let mut frame = Vec::with_capacity(LENGTH_PREFIX_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    pub async fn read<T: Decode<()>>(
+        &self,
+        reader: &mut (impl AsyncRead + Unpin),
+    ) -> Result<T, FrameError> {
+        tokio::time::timeout(self.read_timeout, async {
+            let mut prefix = [0u8; LENGTH_PREFIX_LEN];
+            if let Err(err) = reader.read_exact(&mut prefix).await {
+                return Err(closed_or_io(err));
+            }
+            let mut payload = vec![0u8; self.payload_len(prefix)?];
+            reader.read_exact(&mut payload).await?;
+            self.decode_payload(&payload)
+        })
+        .await
+        .map_err(|_| FrameError::Timeout)?
+    }
+
+    pub async fn write<T: Encode>(
+        &self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        value: &T,
+    ) -> Result<(), FrameError> {
+        writer.write_all(&self.encode(value)?).await?;
+        Ok(())
+    }
+
+    /// Blocking version of [`FrameCodec::read`], the timeout must be set on the reader
+    pub fn read_blocking<T: Decode<()>>(&self, reader: &mut impl Read) -> Result<T, FrameError> {
+        let mut prefix = [0u8; LENGTH_PREFIX_LEN];
+        reader.read_exact(&mut prefix).map_err(closed_or_io)?;
+        let mut payload = vec![0u8; self.payload_len(prefix)?];
+        reader.read_exact(&mut payload)?;
+        self.decode_payload(&payload)
+    }
+
+    pub fn write_blocking<T: Encode>(
+        &self,
+        writer: &mut impl Write,
+        value: &T,
+    ) -> Result<(), FrameError> {
+        writer.write_all(&self.encode(value)?)?;
+        Ok(())
+    }
+
+    fn payload_len(&self, prefix: [u8; LENGTH_PREFIX_LEN]) -> Result<usize, FrameError> {
+        let len = u32::from_be_bytes(prefix) as usize;
+        self.check_len(len)?;
+        Ok(len)
+    }
+
+    fn check_len(&self, len: usize) -> Result<(), FrameError> {
+        if len > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                len,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(())
+    }
+
+    fn decode_payload<T: Decode<()>>(&self, payload: &[u8]) -> Result<T, FrameError> {
+        let (value, used) =
+            bincode::decode_from_slice(payload, bincode_config()).map_err(FrameError::Decode)?;
+        if used != payload.len() {
+            return Err(FrameError::TrailingBytes {
+                len: payload.len(),
+                used,
+            });
+        }
+        Ok(value)
+    }
+}
+
+fn closed_or_io(err: io::Error) -> FrameError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        FrameError::Closed
+    } else {
+        err.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::protocol::{Request, RequestFrame};
+
+    fn frame() -> RequestFrame {
+        RequestFrame {
+            id: 7,
+            request: Request::ModuleCommand {
+                module_name: "ClockModule".to_string(),
+                args: vec!["format".to_string(), "%H:%M".to_string()],
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let codec = FrameCodec::default();
+        let bytes = codec.encode(&frame()).unwrap();
+        let len = u32::from_be_bytes(bytes[..LENGTH_PREFIX_LEN].try_into().unwrap()) as usize;
+        assert_eq!(len, bytes.len() - LENGTH_PREFIX_LEN);
+
+        let decoded: RequestFrame = codec.read_blocking(&mut bytes.as_slice()).unwrap();
+        assert_eq!(decoded, frame());
+    }
+
+    #[tokio::test]
+    async fn async_round_trip() {
+        let codec = FrameCodec::default();
+        let mut bytes = Vec::new();
+        codec.write(&mut bytes, &frame()).await.unwrap();
+        let decoded: RequestFrame = codec.read(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(decoded, frame());
+    }
+
+    #[test]
+    fn empty_input_is_closed() {
+        let codec = FrameCodec::default();
+        let result = codec.read_blocking::<RequestFrame>(&mut [].as_slice());
+        assert!(matches!(result, Err(FrameError::Closed)));
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let codec = FrameCodec::default();
+        let bytes = codec.encode(&frame()).unwrap();
+        for len in 1..bytes.len() {
+            let result = codec.read_blocking::<RequestFrame>(&mut &bytes[..len]);
+            assert!(result.is_err(), "{len} bytes were decoded");
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected_before_reading() {
+        let codec = FrameCodec::new(64, DEFAULT_READ_TIMEOUT);
+        // only the prefix, the payload would fail with an unexpected eof if it was read
+        let prefix = u32::MAX.to_be_bytes();
+        let result = codec.read_blocking::<RequestFrame>(&mut prefix.as_slice());
+        assert!(matches!(
+            result,
+            Err(FrameError::TooLarge { len, max: 64 }) if len == u32::MAX as usize
+        ));
+    }
+
+    #[test]
+    fn oversized_frame_is_not_encoded() {
+        let codec = FrameCodec::new(8, DEFAULT_READ_TIMEOUT);
+        assert!(matches!(
+            codec.encode(&frame()),
+            Err(FrameError::TooLarge { max: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn max_frame_size_is_capped() {
+        let codec = FrameCodec::new(usize::MAX, DEFAULT_READ_TIMEOUT);
+        assert_eq!(codec.max_frame_size, MAX_FRAME_SIZE_LIMIT);
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let codec = FrameCodec::default();
+        let mut bytes = codec.encode(&frame()).unwrap();
+        bytes.push(0);
+        let len = (bytes.len() - LENGTH_PREFIX_LEN) as u32;
+        bytes[..LENGTH_PREFIX_LEN].copy_from_slice(&len.to_be_bytes());
+        let result = codec.read_blocking::<RequestFrame>(&mut bytes.as_slice());
+        assert!(matches!(result, Err(FrameError::TrailingBytes { .. })));
+    }
+
+    #[test]
+    fn garbage_payload_is_rejected() {
+        let codec = FrameCodec::default();
+        let mut bytes = 4u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0xff; 4]);
+        let result = codec.read_blocking::<RequestFrame>(&mut bytes.as_slice());
+        assert!(matches!(result, Err(FrameError::Decode(_))));
+    }
+}
@@ -0,0 +1,488 @@
+//! D-Bus service that mirrors the commands of the IPC socket.
+//!
+//! The daemon owns [`BUS_NAME`] (followed by the instance name for named instances) on the session bus
+//! and exports the `com.github.cr3eperall.dynisland.Ipc` interface at [`OBJECT_PATH`]. Failed requests are returned as `com.github.cr3eperall.dynisland.Error.<Kind>`,
+//! with the same kinds as [`ErrorKind`], and every [`Event`] is also emitted as a signal.
+//!
+//! The bus name is different from the GApplication id, that one is owned by gtk.
+
+use dynisland_core::abi::log;
+use tokio::sync::{broadcast::error::RecvError, mpsc::UnboundedSender};
+use zbus::{interface, object_server::SignalEmitter, DBusError};
+
+use super::{
+    dispatch,
+    protocol::{ErrorKind, Request, RequestId, Response},
+    PendingRequests,
+};
+use crate::{
+    app::BackendServerCommand,
+    events::{Event, EventSender},
+    module_loading::ModuleOrigin,
+};
+
+pub const BUS_NAME: &str = "com.github.cr3eperall.dynisland.Ipc";
+pub const OBJECT_PATH: &str = "/com/github/cr3eperall/dynisland";
+
+#[derive(Debug, DBusError)]
+#[zbus(prefix = "com.github.cr3eperall.dynisland.Error")]
+pub enum DbusError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    Internal(String),
+    Timeout(String),
+    ModuleNotFound(String),
+    ActivityNotFound(String),
+    InvalidActivityIdentifier(String),
+    InvalidArgument(String),
+    NoLayout(String),
+    CommandFailed(String),
+    ConfigRejected(String),
+}
+
+impl DbusError {
+    fn new(kind: ErrorKind, message: String) -> Self {
+        match kind {
+            ErrorKind::Timeout => Self::Timeout(message),
+            ErrorKind::ModuleNotFound => Self::ModuleNotFound(message),
+            ErrorKind::ActivityNotFound => Self::ActivityNotFound(message),
+            ErrorKind::InvalidActivityIdentifier => Self::InvalidActivityIdentifier(message),
+            ErrorKind::InvalidArgument => Self::InvalidArgument(message),
+            ErrorKind::NoLayout => Self::NoLayout(message),
+            ErrorKind::CommandFailed => Self::CommandFailed(message),
+            ErrorKind::ConfigRejected => Self::ConfigRejected(message),
+            // the client side kinds can't happen here
+            ErrorKind::Internal
+            | ErrorKind::NotRunning
+            | ErrorKind::ProtocolMismatch
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AlreadyRunning
+            | ErrorKind::StartupFailed => Self::Internal(message),
+        }
+    }
+}
+
+struct IpcService {
+    server_send: UnboundedSender<(RequestId, BackendServerCommand)>,
+    pending: PendingRequests,
+}
+
+impl IpcService {
+    async fn request(&self, request: Request) -> Result<Response, DbusError> {
+        let response = dispatch(request, &self.server_send, &self.pending)
+            .await
+            .map_err(|err| DbusError::Internal(err.to_string()))?;
+        match response {
+            Response::Error { kind, message } => Err(DbusError::new(kind, message)),
+            response => Ok(response),
+        }
+    }
+}
+
+#[interface(name = "com.github.cr3eperall.dynisland.Ipc")]
+impl IpcService {
+    async fn reload(&self) -> Result<(), DbusError> {
+        self.request(Request::Reload).await.map(|_| ())
+    }
+
+    async fn open_inspector(&self) -> Result<(), DbusError> {
+        self.request(Request::OpenInspector).await.map(|_| ())
+    }
+
+    async fn health_check(&self) -> Result<(), DbusError> {
+        self.request(Request::HealthCheck).await.map(|_| ())
+    }
+
+    /// Returns the same JSON object as `dynisland status --json`
+    async fn status(&self) -> Result<String, DbusError> {
+        match self.request(Request::Status).await? {
+            response @ Response::Status(_) => Ok(response.to_json().to_string()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Merges `filter` into the log filter (an empty one leaves it as it is),
+    /// or goes back to the startup filter with `reset`. Returns the filter in use
+    async fn log_filter(&self, filter: String, reset: bool) -> Result<String, DbusError> {
+        let request = Request::LogFilter {
+            filter: (!filter.is_empty()).then_some(filter),
+            reset,
+        };
+        match self.request(request).await? {
+            Response::Message(filter) => Ok(filter),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Writes back the config files that were last applied without errors, returns the restored files
+    async fn rollback_config(&self) -> Result<String, DbusError> {
+        match self.request(Request::RollbackConfig).await? {
+            Response::Message(message) => Ok(message),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `duration` is in milliseconds, 0 uses the default duration
+    async fn activity_notification(
+        &self,
+        activity_identifier: String,
+        mode: u8,
+        duration: u64,
+    ) -> Result<(), DbusError> {
+        self.request(Request::ActivityNotification {
+            activity_identifier,
+            mode,
+            duration: (duration != 0).then_some(duration),
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// Returns `(module, activity, window)`, window is empty if the activity is on the default one
+    async fn list_activities(&self) -> Result<Vec<(String, String, String)>, DbusError> {
+        match self.request(Request::ListActivities).await? {
+            Response::Activities(activities) => Ok(activities
+                .into_iter()
+                .map(|activity| {
+                    (
+                        activity.module,
+                        activity.activity,
+                        activity.metadata.window.unwrap_or_default(),
+                    )
+                })
+                .collect()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Returns `(name, origin)`, origin is either `embedded` or the path of the `.so` file
+    async fn list_loaded_modules(&self) -> Result<Vec<(String, String)>, DbusError> {
+        match self.request(Request::ListLoadedModules).await? {
+            Response::Modules(modules) => Ok(modules
+                .into_iter()
+                .map(|module| {
+                    let origin = match module.origin {
+                        ModuleOrigin::Embedded => "embedded".to_string(),
+                        ModuleOrigin::Library(path) => path,
+                    };
+                    (module.name, origin)
+                })
+                .collect()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn module_command(
+        &self,
+        module_name: String,
+        args: Vec<String>,
+    ) -> Result<String, DbusError> {
+        match self
+            .request(Request::ModuleCommand { module_name, args })
+            .await?
+        {
+            Response::ModuleOutput { output, .. } => Ok(output),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn layout_command(&self, args: Vec<String>) -> Result<String, DbusError> {
+        match self.request(Request::LayoutCommand { args }).await? {
+            Response::LayoutOutput { output, .. } => Ok(output),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    #[zbus(signal)]
+    async fn activity_added(
+        emitter: &SignalEmitter<'_>,
+        module: &str,
+        activity: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn activity_removed(
+        emitter: &SignalEmitter<'_>,
+        module: &str,
+        activity: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn mode_changed(
+        emitter: &SignalEmitter<'_>,
+        module: &str,
+        activity: &str,
+        mode: &str,
+    ) -> zbus::Result<()>;
+
+    /// `duration` is 0 if the default duration is used
+    #[zbus(signal)]
+    async fn notification_requested(
+        emitter: &SignalEmitter<'_>,
+        module: &str,
+        activity: &str,
+        mode: &str,
+        duration: u64,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn config_reloaded(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn module_load_failed(
+        emitter: &SignalEmitter<'_>,
+        module: &str,
+        error: &str,
+    ) -> zbus::Result<()>;
+}
+
+fn unexpected(response: Response) -> DbusError {
+    DbusError::Internal(format!("unexpected response from the daemon: {response:?}"))
+}
+
+/// Owns `bus_name` on the session bus and forwards the events as signals until the event channel is closed
+pub async fn serve(
+    bus_name: String,
+    server_send: UnboundedSender<(RequestId, BackendServerCommand)>,
+    pending: PendingRequests,
+    event_send: EventSender,
+) -> zbus::Result<()> {
+    let bus = zbus::connection::Builder::session()?;
+    serve_on(bus, bus_name, server_send, pending, event_send).await
+}
+
+async fn serve_on(
+    bus: zbus::connection::Builder<'_>,
+    bus_name: String,
+    server_send: UnboundedSender<(RequestId, BackendServerCommand)>,
+    pending: PendingRequests,
+    event_send: EventSender,
+) -> zbus::Result<()> {
+    let mut event_recv = event_send.subscribe();
+    let service = IpcService {
+        server_send,
+        pending,
+    };
+    // the handlers use the tokio timers, so the executor of zbus runs on this runtime
+    // instead of its own thread
+    let connection = bus
+        .name(bus_name.as_str())?
+        .serve_at(OBJECT_PATH, service)?
+        .internal_executor(false)
+        .build()
+        .await?;
+    let executor = tokio::spawn({
+        let connection = connection.clone();
+        async move {
+            loop {
+                connection.executor().tick().await;
+            }
+        }
+    });
+    log::info!("D-Bus service started at {bus_name}");
+    let emitter = SignalEmitter::new(&connection, OBJECT_PATH)?;
+    loop {
+        match event_recv.recv().await {
+            Ok(event) => {
+                if let Err(err) = emit_signal(&emitter, &event).await {
+                    log::warn!("failed to emit D-Bus signal for {event:?}: {err}");
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("D-Bus service is too slow, {skipped} signals were skipped");
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    executor.abort();
+    Ok(())
+}
+
+async fn emit_signal(emitter: &SignalEmitter<'_>, event: &Event) -> zbus::Result<()> {
+    match event {
+        Event::ActivityAdded { module, activity } => {
+            IpcService::activity_added(emitter, module, activity).await
+        }
+        Event::ActivityRemoved { module, activity } => {
+            IpcService::activity_removed(emitter, module, activity).await
+        }
+        Event::ModeChanged {
+            module,
+            activity,
+            mode,
+        } => IpcService::mode_changed(emitter, module, activity, mode).await,
+        Event::NotificationRequested {
+            module,
+            activity,
+            mode,
+            duration,
+        } => {
+            IpcService::notification_requested(
+                emitter,
+                module,
+                activity,
+                mode,
+                duration.unwrap_or(0),
+            )
+            .await
+        }
+        Event::ConfigReloaded => IpcService::config_reloaded(emitter).await,
+        Event::ModuleLoadFailed { module, error } => {
+            IpcService::module_load_failed(emitter, module, error).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        pin::Pin,
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc::unbounded_channel;
+    use zbus::export::futures_core::Stream;
+
+    use super::*;
+    use crate::{events, ipc::protocol::ModuleInfo};
+
+    const TEST_BUS_NAME: &str = "com.github.cr3eperall.dynisland.Ipc.test";
+
+    /// A private session bus, it's killed when this is dropped
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        /// `None` if dbus-daemon is not installed
+        fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+            Some(Self {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Answers like the backend server of a daemon with only the clock module
+    async fn fake_backend(
+        mut server_recv: tokio::sync::mpsc::UnboundedReceiver<(RequestId, BackendServerCommand)>,
+        pending: PendingRequests,
+    ) {
+        while let Some((id, command)) = server_recv.recv().await {
+            let response = match command {
+                BackendServerCommand::ListLoadedModules => Response::Modules(vec![ModuleInfo {
+                    name: "ClockModule".to_string(),
+                    origin: ModuleOrigin::Embedded,
+                }]),
+                BackendServerCommand::ModuleCliCommand(module, _) => {
+                    Response::error(ErrorKind::ModuleNotFound, format!("{module} not found"))
+                }
+                _ => Response::Ok,
+            };
+            pending.resolve(id, response);
+        }
+    }
+
+    #[tokio::test]
+    async fn methods_and_signals_on_a_private_bus() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("dbus-daemon not found, skipping");
+            return;
+        };
+        let (server_send, server_recv) = unbounded_channel();
+        let pending = PendingRequests::default();
+        let event_send = tokio::sync::broadcast::channel(events::EVENT_CHANNEL_CAPACITY).0;
+        tokio::spawn(fake_backend(server_recv, pending.clone()));
+        let service_bus = zbus::connection::Builder::address(bus.address.as_str()).unwrap();
+        tokio::spawn(serve_on(
+            service_bus,
+            TEST_BUS_NAME.to_string(),
+            server_send,
+            pending,
+            event_send.clone(),
+        ));
+
+        let client = zbus::connection::Builder::address(bus.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let proxy = zbus::Proxy::new(
+            &client,
+            TEST_BUS_NAME,
+            OBJECT_PATH,
+            "com.github.cr3eperall.dynisland.Ipc",
+        )
+        .await
+        .unwrap();
+        // the service owns the name asynchronously
+        let mut ready = false;
+        for _ in 0..50 {
+            if proxy.call_method("HealthCheck", &()).await.is_ok() {
+                ready = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(ready, "the service didn't own {TEST_BUS_NAME}");
+
+        let modules: Vec<(String, String)> = proxy.call("ListLoadedModules", &()).await.unwrap();
+        assert_eq!(
+            modules,
+            vec![("ClockModule".to_string(), "embedded".to_string())]
+        );
+
+        let err = proxy
+            .call::<_, _, String>("ModuleCommand", &("MissingModule", vec!["args"]))
+            .await
+            .unwrap_err();
+        match err {
+            zbus::Error::MethodError(name, _, _) => assert_eq!(
+                name.as_str(),
+                "com.github.cr3eperall.dynisland.Error.ModuleNotFound"
+            ),
+            err => panic!("unexpected error: {err}"),
+        }
+
+        // checked by the service itself, the backend isn't involved
+        let err = proxy
+            .call::<_, _, ()>("ActivityNotification", &("no-module", 1u8, 0u64))
+            .await
+            .unwrap_err();
+        match err {
+            zbus::Error::MethodError(name, _, _) => assert_eq!(
+                name.as_str(),
+                "com.github.cr3eperall.dynisland.Error.InvalidActivityIdentifier"
+            ),
+            err => panic!("unexpected error: {err}"),
+        }
+
+        let mut reloaded = proxy.receive_signal("ConfigReloaded").await.unwrap();
+        event_send.send(Event::ConfigReloaded).unwrap();
+        let next_signal = std::future::poll_fn(|cx| Pin::new(&mut reloaded).poll_next(cx));
+        tokio::time::timeout(Duration::from_secs(5), next_signal)
+            .await
+            .expect("ConfigReloaded was not emitted")
+            .unwrap();
+    }
+}
@@ -0,0 +1,719 @@
+pub mod codec;
+pub mod dbus;
+pub mod protocol;
+
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use codec::{FrameCodec, FrameError};
+use dynisland_core::{
+    abi::{log, module::ActivityIdentifier},
+    graphics::activity_widget::boxed_activity_mode::ActivityMode,
+};
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    unistd::Pid,
+};
+use protocol::{
+    ErrorKind, Handshake, NoHandshake, ProtocolMismatch, Request, RequestFrame, RequestId,
+    Response, ResponseFrame, PROTOCOL_VERSION, STOP_MAGIC,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{unix::UCred, UnixListener, UnixStream},
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+};
+
+use crate::{
+    app::BackendServerCommand,
+    config::IpcConfig,
+    events::{Event, EventFilter, EventSender},
+    logging,
+    readiness::{Readiness, Stage},
+    shutdown::SHUTDOWN_DEADLINE,
+};
+
+/// Id used for commands that don't come from an IPC client (e.g. the config watcher),
+/// their responses are discarded
+pub const INTERNAL_REQUEST_ID: RequestId = 0;
+
+/// Id used for commands activated as GApplication actions, nobody can receive their responses so they are logged
+pub const ACTION_REQUEST_ID: RequestId = RequestId::MAX;
+
+/// How long a connection waits for the backend to answer before giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// Requests that were sent to the backend server and are waiting for a response,
+/// shared between the socket and the D-Bus service
+#[derive(Clone, Default)]
+pub struct PendingRequests {
+    last_id: Arc<AtomicU64>,
+    senders: Arc<Mutex<HashMap<RequestId, oneshot::Sender<Response>>>>,
+}
+
+impl PendingRequests {
+    fn register(&self) -> (RequestId, oneshot::Receiver<Response>) {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (response_send, response_recv) = oneshot::channel();
+        self.senders.lock().unwrap().insert(id, response_send);
+        (id, response_recv)
+    }
+
+    fn cancel(&self, id: RequestId) {
+        self.senders.lock().unwrap().remove(&id);
+    }
+
+    fn resolve(&self, id: RequestId, response: Response) {
+        match self.senders.lock().unwrap().remove(&id) {
+            Some(response_send) => {
+                let _ = response_send.send(response);
+            }
+            None if id == INTERNAL_REQUEST_ID => {}
+            None if id == ACTION_REQUEST_ID => {
+                log::info!("action response:\n{response}");
+            }
+            None => {
+                log::debug!("discarding response to request {id}, the client is gone");
+            }
+        }
+    }
+}
+
+/// What the daemon shares with the IPC server, it's kept across socket restarts
+#[derive(Clone)]
+pub struct IpcServer {
+    pub server_send: UnboundedSender<(RequestId, BackendServerCommand)>,
+    pub pending: PendingRequests,
+    pub access: IpcConfig,
+    pub event_send: EventSender,
+    /// Replayed to every subscriber, see [`App::startup_events`](crate::app::App::startup_events)
+    pub startup_events: Vec<Event>,
+    pub readiness: Readiness,
+}
+
+pub async fn open_socket(
+    runtime_path: &Path,
+    server_response_recv: &mut UnboundedReceiver<(RequestId, Response)>,
+    server: &IpcServer,
+) -> Result<()> {
+    let IpcServer {
+        server_send,
+        pending,
+        access,
+        event_send,
+        startup_events,
+        readiness,
+    } = server;
+    let socket_path = runtime_path.join("dynisland.sock");
+    // other users can't reach the socket unless they are allowed to, the peer credentials are checked anyway
+    let (dir_mode, socket_mode) = if access.is_shared() {
+        (0o711, 0o666)
+    } else {
+        (0o700, 0o600)
+    };
+    restrict_runtime_dir(runtime_path, dir_mode)?;
+    if access.is_shared() {
+        warn_if_unreachable(runtime_path);
+    }
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)?;
+    std::fs::set_permissions(&socket_path, Permissions::from_mode(socket_mode))?;
+    readiness.mark(Stage::SocketBound);
+    let (stop_send, mut stop_recv) = unbounded_channel::<()>();
+    let context = ConnectionContext {
+        server_send: server_send.clone(),
+        pending: pending.clone(),
+        stop_send,
+        event_send: event_send.clone(),
+        startup_events: startup_events.clone(),
+        codec: access.codec(),
+    };
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _socket)) => stream,
+                    Err(err) => {
+                        log::warn!("failed to accept IPC connection: {err}");
+                        // avoid spinning if it keeps failing (e.g. too many open files)
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let peer = match stream.peer_cred() {
+                    Ok(peer) => peer,
+                    Err(err) => {
+                        log::warn!("failed to get the peer credentials, closing connection: {err}");
+                        continue;
+                    }
+                };
+                let connection = handle_connection(
+                    stream,
+                    peer,
+                    is_authorized(&peer, access),
+                    context.clone(),
+                );
+                tokio::spawn(async move {
+                    if let Err(err) = connection.await {
+                        log::warn!("IPC connection closed with an error: {err}");
+                    }
+                });
+            }
+            Some((id, response)) = server_response_recv.recv() => {
+                pending.resolve(id, response);
+            }
+            Some(()) = stop_recv.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the permissions of the runtime directory, unless it's shared with other users (like `/tmp`)
+fn restrict_runtime_dir(runtime_path: &Path, mode: u32) -> Result<()> {
+    let metadata = std::fs::metadata(runtime_path)?;
+    let is_sticky = metadata.mode() & 0o1000 != 0;
+    if metadata.uid() != nix::unistd::getuid().as_raw() || is_sticky {
+        log::warn!(
+            "not changing the permissions of {}, it's not owned by the daemon user",
+            runtime_path.display()
+        );
+        return Ok(());
+    }
+    std::fs::set_permissions(runtime_path, Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// Warns if a parent of the runtime directory can't be traversed by other users,
+/// like the default `$XDG_RUNTIME_DIR` (mode 0700), the allowed users can't reach the socket in that case
+fn warn_if_unreachable(runtime_path: &Path) {
+    let Some(closed) = runtime_path
+        .ancestors()
+        .skip(1)
+        .find(|dir| std::fs::metadata(dir).is_ok_and(|metadata| metadata.mode() & 0o001 == 0))
+    else {
+        return;
+    };
+    log::warn!(
+        "other users can't enter {}, allowed_uids and allowed_gids have no effect unless debug.runtime_path is outside of it",
+        closed.display()
+    );
+}
+
+/// The user running the daemon is always allowed, the others only if they are in [`IpcConfig`]
+fn is_authorized(peer: &UCred, access: &IpcConfig) -> bool {
+    peer.uid() == nix::unistd::getuid().as_raw()
+        || access.allowed_uids.contains(&peer.uid())
+        || access.allowed_gids.contains(&peer.gid())
+        || !access.allowed_gids.is_empty()
+            && peer.pid().is_some_and(|pid| {
+                supplementary_groups(pid)
+                    .iter()
+                    .any(|gid| access.allowed_gids.contains(gid))
+            })
+}
+
+/// Supplementary groups of a process, from the `Groups:` line of `/proc/<pid>/status`
+fn supplementary_groups(pid: i32) -> Vec<u32> {
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{pid}/status")) else {
+        return Vec::new();
+    };
+    parse_groups(&status)
+}
+
+fn parse_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Everything a connection needs from the daemon
+#[derive(Clone)]
+struct ConnectionContext {
+    server_send: UnboundedSender<(RequestId, BackendServerCommand)>,
+    pending: PendingRequests,
+    stop_send: UnboundedSender<()>,
+    event_send: EventSender,
+    startup_events: Vec<Event>,
+    codec: FrameCodec,
+}
+
+async fn handle_connection(
+    mut stream: UnixStream,
+    peer: UCred,
+    authorized: bool,
+    context: ConnectionContext,
+) -> Result<()> {
+    let ConnectionContext {
+        server_send,
+        pending,
+        stop_send,
+        event_send,
+        startup_events,
+        codec,
+    } = context;
+    let pid = peer
+        .pid()
+        .map_or("unknown".to_string(), |pid| pid.to_string());
+    match server_handshake(&mut stream, codec.read_timeout).await? {
+        Some(handshake) if !authorized => {
+            log::warn!(
+                "rejected connection from uid {} (pid {pid}), it's not allowed to use the socket",
+                peer.uid()
+            );
+            // incompatible clients can't read the response anyway
+            if handshake.is_compatible() {
+                let RequestFrame { id, .. } = read_request(&mut stream, &codec, &pid).await?;
+                let response = Response::error(
+                    ErrorKind::PermissionDenied,
+                    format!("uid {} is not allowed to use this socket", peer.uid()),
+                );
+                let _ = codec
+                    .write(&mut stream, &ResponseFrame { id, response })
+                    .await;
+            }
+            stream.shutdown().await?;
+            return Ok(());
+        }
+        Some(handshake) if handshake.is_compatible() => {}
+        Some(handshake) => {
+            log::warn!(
+                "client is protocol v{}, daemon is v{PROTOCOL_VERSION}",
+                handshake.protocol_version
+            );
+            if wait_for_stop(&mut stream, codec.read_timeout).await {
+                log::info!("stop requested by incompatible client (pid {pid})");
+                request_with_timeout(
+                    &server_send,
+                    &pending,
+                    BackendServerCommand::Stop,
+                    SHUTDOWN_DEADLINE + RESPONSE_TIMEOUT,
+                )
+                .await?;
+                stop_send.send(())?;
+            }
+            return Ok(());
+        }
+        None => {
+            log::warn!("invalid handshake received, closing connection");
+            return Ok(());
+        }
+    }
+    let RequestFrame {
+        id,
+        request: message,
+    } = read_request(&mut stream, &codec, &pid).await?;
+    log::info!("IPC request from pid {pid}: {message:?}");
+    let response = match message {
+        Request::Kill | Request::Restart => {
+            let command = match message {
+                Request::Restart => BackendServerCommand::Restart,
+                _ => BackendServerCommand::Stop,
+            };
+            let response = request_with_timeout(
+                &server_send,
+                &pending,
+                command,
+                SHUTDOWN_DEADLINE + RESPONSE_TIMEOUT,
+            )
+            .await?;
+            // a restart that failed before the shutdown leaves the daemon running
+            let stopping = message == Request::Kill || response.is_ok();
+            let _ = codec
+                .write(&mut stream, &ResponseFrame { id, response })
+                .await;
+            stream.shutdown().await?;
+            if stopping {
+                stop_send.send(())?;
+            }
+            return Ok(());
+        }
+        Request::Subscribe(filter) => {
+            return stream_events(stream, event_send, startup_events, filter).await;
+        }
+        message => dispatch(message, &server_send, &pending).await?,
+    };
+    let _ = codec
+        .write(&mut stream, &ResponseFrame { id, response })
+        .await;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Malformed frames only close the connection they came from
+async fn read_request(
+    stream: &mut UnixStream,
+    codec: &FrameCodec,
+    pid: &str,
+) -> Result<RequestFrame> {
+    codec
+        .read(stream)
+        .await
+        .with_context(|| format!("rejected request from pid {pid}"))
+}
+
+/// `activity@module`, with exactly one `@` and neither part empty
+pub(crate) fn parse_activity_identifier(identifier: &str) -> Option<ActivityIdentifier> {
+    match identifier.split('@').collect::<Vec<_>>().as_slice() {
+        [activity, module] if !activity.is_empty() && !module.is_empty() => {
+            Some(ActivityIdentifier::new(module, activity))
+        }
+        _ => None,
+    }
+}
+
+/// Handles the requests that only need an answer from the backend server,
+/// [`Request::Kill`], [`Request::Restart`] and [`Request::Subscribe`] depend on the connection and are handled by the caller
+pub(crate) async fn dispatch(
+    message: Request,
+    server_send: &UnboundedSender<(RequestId, BackendServerCommand)>,
+    pending: &PendingRequests,
+) -> Result<Response> {
+    let response = match message {
+        Request::Reload => {
+            request(server_send, pending, BackendServerCommand::ReloadConfig).await?
+        }
+        Request::OpenInspector => {
+            request(server_send, pending, BackendServerCommand::OpenInspector).await?
+        }
+        Request::HealthCheck => {
+            log::info!("received HealthCheck, Everything OK");
+            Response::Ok
+        }
+        Request::Status => request(server_send, pending, BackendServerCommand::Status).await?,
+        Request::RollbackConfig => {
+            request(server_send, pending, BackendServerCommand::RollbackConfig).await?
+        }
+        // the logger is global, the UI thread is not involved
+        Request::LogFilter { filter, reset } => {
+            let filter = match (filter, reset) {
+                (_, true) => logging::reset_filter(),
+                (Some(filter), false) => match filter.parse() {
+                    Ok(filter) => logging::update_filter(filter),
+                    Err(err) => {
+                        return Ok(Response::error(
+                            ErrorKind::InvalidArgument,
+                            format!("{err:#}"),
+                        ))
+                    }
+                },
+                (None, false) => logging::filter(),
+            };
+            Response::Message(filter.to_string())
+        }
+        Request::ActivityNotification {
+            activity_identifier,
+            mode,
+            duration,
+        } => {
+            let Some(activity_id) = parse_activity_identifier(&activity_identifier) else {
+                log::error!("invalid activity identifier: {activity_identifier}");
+                return Ok(Response::error(
+                    ErrorKind::InvalidActivityIdentifier,
+                    format!("{activity_identifier} is not in the activity@module format"),
+                ));
+            };
+            match ActivityMode::try_from(mode) {
+                Ok(mode) => {
+                    request(
+                        server_send,
+                        pending,
+                        BackendServerCommand::ActivityNotification(activity_id, mode, duration),
+                    )
+                    .await?
+                }
+                Err(err) => Response::error(ErrorKind::InvalidArgument, err),
+            }
+        }
+        Request::ListActivities => {
+            request(server_send, pending, BackendServerCommand::ListActivities).await?
+        }
+        Request::ListLoadedModules => {
+            request(
+                server_send,
+                pending,
+                BackendServerCommand::ListLoadedModules,
+            )
+            .await?
+        }
+        Request::ModuleCommand { module_name, args } => {
+            request(
+                server_send,
+                pending,
+                BackendServerCommand::ModuleCliCommand(module_name, args.join(" ")),
+            )
+            .await?
+        }
+        Request::LayoutCommand { args } => {
+            request(
+                server_send,
+                pending,
+                BackendServerCommand::LayoutCliCommand(args.join(" ")),
+            )
+            .await?
+        }
+        Request::Kill | Request::Restart | Request::Subscribe(_) => Response::error(
+            ErrorKind::InvalidArgument,
+            "this request needs a socket connection",
+        ),
+    };
+    Ok(response)
+}
+
+/// Writes every event matching `filter` to the stream until the client disconnects.
+///
+/// The events that happened before the socket was opened (e.g. module load failures)
+/// are sent first, so subscribers don't miss them.
+/// The client doesn't send anything after the request, so the connection is closed as soon as
+/// its read half ends, without waiting for an event to fail to be written
+async fn stream_events(
+    mut stream: UnixStream,
+    event_send: EventSender,
+    startup_events: Vec<Event>,
+    filter: EventFilter,
+) -> Result<()> {
+    let mut event_recv = event_send.subscribe();
+    for event in startup_events.iter().filter(|event| filter.matches(event)) {
+        write_event(&mut stream, event).await?;
+    }
+    let (mut read_half, mut write_half) = stream.split();
+    let mut discarded = [0u8; 64];
+    loop {
+        tokio::select! {
+            received = event_recv.recv() => match received {
+                Ok(event) => {
+                    if filter.matches(&event) {
+                        write_event(&mut write_half, &event).await?;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("subscriber is too slow, {skipped} events were skipped");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            read = read_half.read(&mut discarded) => match read {
+                Ok(0) | Err(_) => {
+                    log::debug!("subscriber disconnected");
+                    return Ok(());
+                }
+                Ok(_) => {}
+            },
+        }
+    }
+    write_half.shutdown().await?;
+    Ok(())
+}
+
+async fn write_event(stream: &mut (impl AsyncWrite + Unpin), event: &Event) -> Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    Ok(())
+}
+
+/// Sends a command to the backend server and waits for the response addressed to it,
+/// answers with [`ErrorKind::Timeout`] if the backend didn't answer in time
+async fn request(
+    server_send: &UnboundedSender<(RequestId, BackendServerCommand)>,
+    pending: &PendingRequests,
+    command: BackendServerCommand,
+) -> Result<Response> {
+    request_with_timeout(server_send, pending, command, RESPONSE_TIMEOUT).await
+}
+
+async fn request_with_timeout(
+    server_send: &UnboundedSender<(RequestId, BackendServerCommand)>,
+    pending: &PendingRequests,
+    command: BackendServerCommand,
+    timeout: Duration,
+) -> Result<Response> {
+    let (id, response_recv) = pending.register();
+    if let Err(err) = server_send.send((id, command)) {
+        pending.cancel(id);
+        return Err(err.into());
+    }
+    match tokio::time::timeout(timeout, response_recv).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(_)) => Ok(Response::error(
+            ErrorKind::Internal,
+            "the backend dropped the request",
+        )),
+        Err(_) => {
+            log::warn!("request {id} timed out");
+            pending.cancel(id);
+            Ok(Response::error(
+                ErrorKind::Timeout,
+                "the daemon didn't answer in time",
+            ))
+        }
+    }
+}
+
+/// Reads the client's handshake and always answers with ours, so the client can report the mismatch
+async fn server_handshake(
+    stream: &mut UnixStream,
+    read_timeout: Duration,
+) -> Result<Option<Handshake>> {
+    let mut handshake_bytes = [0u8; Handshake::LEN];
+    tokio::time::timeout(read_timeout, stream.read_exact(&mut handshake_bytes)).await??;
+    let handshake = Handshake::from_bytes(&handshake_bytes);
+    if handshake.is_some() {
+        stream.write_all(&Handshake::default().to_bytes()).await?;
+    }
+    Ok(handshake)
+}
+
+/// After a failed handshake the client is only allowed to ask the daemon to stop
+async fn wait_for_stop(stream: &mut UnixStream, read_timeout: Duration) -> bool {
+    let mut stop_bytes = [0u8; 4];
+    matches!(
+        tokio::time::timeout(read_timeout, stream.read_exact(&mut stop_bytes)).await,
+        Ok(Ok(_))
+    ) && stop_bytes == STOP_MAGIC
+}
+
+/// `dynisland kill` as sent by the clients older than the handshake:
+/// the length of the bincode of `SubCommands::Kill`, then its variant index
+const LEGACY_KILL: [u8; 5] = [0, 0, 0, 1, 5];
+
+/// Asks a daemon older than the handshake to stop.
+///
+/// The handshake can't be used with it, it reads the magic as the length of a huge request
+/// and stops answering, so this must be sent on a new connection instead of the handshake
+pub fn send_legacy_kill(
+    mut stream: std::os::unix::net::UnixStream,
+    codec: &FrameCodec,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(codec.read_timeout + RESPONSE_TIMEOUT))?;
+    stream.write_all(&LEGACY_KILL)?;
+    // it answers with a bincode string, or nothing if the ui thread doesn't answer in time
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(())
+}
+
+/// Pid of the process listening on the socket, it's known also when the daemon doesn't answer
+pub fn peer_pid(stream: &std::os::unix::net::UnixStream) -> Option<Pid> {
+    let credentials = getsockopt(stream, PeerCredentials).ok()?;
+    (credentials.pid() > 0).then(|| Pid::from_raw(credentials.pid()))
+}
+
+static NEXT_CLIENT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns `None` if the daemon closed the connection without answering
+pub fn send_recv_message(
+    mut stream: std::os::unix::net::UnixStream,
+    message: &Request,
+    codec: &FrameCodec,
+) -> Result<Option<Response>> {
+    stream.set_nonblocking(false)?;
+    // the daemon can take up to RESPONSE_TIMEOUT to process the request, the shutdown can take longer
+    let mut read_timeout = codec.read_timeout + RESPONSE_TIMEOUT;
+    if matches!(message, Request::Kill | Request::Restart) {
+        read_timeout += SHUTDOWN_DEADLINE;
+    }
+    stream.set_read_timeout(Some(read_timeout))?;
+
+    let daemon_handshake = client_handshake(&mut stream)?;
+    if !daemon_handshake.is_compatible() {
+        if *message == Request::Kill {
+            // this is the only message every version of the daemon understands
+            stream.write_all(&STOP_MAGIC)?;
+            return Ok(None);
+        }
+        return Err(ProtocolMismatch {
+            daemon_version: daemon_handshake.protocol_version,
+            client_version: PROTOCOL_VERSION,
+        }
+        .into());
+    }
+
+    let id = NEXT_CLIENT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let frame = RequestFrame {
+        id,
+        request: message.clone(),
+    };
+    codec.write_blocking(&mut stream, &frame)?;
+    let frame: ResponseFrame = match codec.read_blocking(&mut stream) {
+        Ok(frame) => frame,
+        Err(FrameError::Closed) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if frame.id != id {
+        bail!("received response to request {}, expected {id}", frame.id);
+    }
+    Ok(Some(frame.response))
+}
+
+/// Subscribes to the daemon events and copies them to stdout until the daemon closes the connection
+pub fn subscribe(
+    mut stream: std::os::unix::net::UnixStream,
+    filter: EventFilter,
+    codec: &FrameCodec,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(codec.read_timeout + RESPONSE_TIMEOUT))?;
+
+    let daemon_handshake = client_handshake(&mut stream)?;
+    if !daemon_handshake.is_compatible() {
+        return Err(ProtocolMismatch {
+            daemon_version: daemon_handshake.protocol_version,
+            client_version: PROTOCOL_VERSION,
+        }
+        .into());
+    }
+
+    let frame = RequestFrame {
+        id: NEXT_CLIENT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        request: Request::Subscribe(filter),
+    };
+    codec.write_blocking(&mut stream, &frame)?;
+    // events can be minutes apart
+    stream.set_read_timeout(None)?;
+
+    let mut stdout = std::io::stdout().lock();
+    for line in BufReader::new(stream).lines() {
+        writeln!(stdout, "{}", line?)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// A read timeout is kept as the [`std::io::Error`], the daemon is there but busy
+fn client_handshake(stream: &mut std::os::unix::net::UnixStream) -> Result<Handshake> {
+    stream.write_all(&Handshake::default().to_bytes())?;
+    let mut handshake_bytes = [0u8; Handshake::LEN];
+    match stream.read_exact(&mut handshake_bytes) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Err(NoHandshake { closed: true }.into())
+        }
+        Err(err) => return Err(err).context("failed to read the handshake of the daemon"),
+    }
+    Handshake::from_bytes(&handshake_bytes).ok_or_else(|| NoHandshake { closed: false }.into())
+}
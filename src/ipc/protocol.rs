@@ -0,0 +1,591 @@
+//! Wire format used on the IPC socket.
+//!
+//! Every connection starts with a fixed-size [`Handshake`] that is the same in every version:
+//! the client sends [`HANDSHAKE_MAGIC`] followed by its [`PROTOCOL_VERSION`],
+//! and the daemon answers with its own.
+//! If the versions match, the client sends a [`RequestFrame`]
+//! and the daemon answers with a [`ResponseFrame`] carrying the same id,
+//! both are length-prefixed by the [`FrameCodec`](super::codec::FrameCodec).
+//!
+//! If they don't match, the only thing the client can still send is [`STOP_MAGIC`],
+//! this way `dynisland kill` and `dynisland restart` keep working after an upgrade.
+//!
+//! With `--json` the client prints [`Response::to_json`] instead of the human readable text,
+//! the schema is documented there and in the README.
+
+use std::{
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bincode::{Decode, Encode};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    cli::{ConfigCommand, SubCommands},
+    events::EventFilter,
+    module_loading::ModuleOrigin,
+};
+
+/// Bump this in the first release where [`Request`] or [`Response`] change,
+/// not at every change between two releases
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const HANDSHAKE_MAGIC: [u8; 4] = *b"DYNI";
+pub const STOP_MAGIC: [u8; 4] = *b"STOP";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Handshake {
+    pub const LEN: usize = 8;
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..4].copy_from_slice(&HANDSHAKE_MAGIC);
+        bytes[4..].copy_from_slice(&self.protocol_version.to_be_bytes());
+        bytes
+    }
+
+    /// Returns `None` if the peer is not speaking the dynisland protocol
+    pub fn from_bytes(bytes: &[u8; Self::LEN]) -> Option<Self> {
+        if bytes[..4] != HANDSHAKE_MAGIC {
+            return None;
+        }
+        let protocol_version = u32::from_be_bytes(bytes[4..].try_into().unwrap());
+        Some(Self { protocol_version })
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+#[derive(Debug)]
+pub struct ProtocolMismatch {
+    pub daemon_version: u32,
+    pub client_version: u32,
+}
+
+impl Display for ProtocolMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "daemon is protocol v{}, client is v{}, run `dynisland restart`",
+            self.daemon_version, self.client_version
+        )
+    }
+}
+
+impl std::error::Error for ProtocolMismatch {}
+
+/// The daemon didn't answer with a [`Handshake`], it's older than the handshake
+#[derive(Debug)]
+pub struct NoHandshake {
+    /// It closed the connection instead of answering with something else
+    pub closed: bool,
+}
+
+impl Display for NoHandshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.closed {
+            write!(
+                f,
+                "the daemon closed the connection without answering the handshake"
+            )?;
+        } else {
+            write!(f, "the daemon didn't answer with a handshake")?;
+        }
+        write!(
+            f,
+            ", it's probably an older version, run `dynisland restart`"
+        )
+    }
+}
+
+impl std::error::Error for NoHandshake {}
+
+pub type RequestId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct RequestFrame {
+    pub id: RequestId,
+    pub request: Request,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ResponseFrame {
+    pub id: RequestId,
+    pub response: Response,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum Request {
+    Reload,
+    OpenInspector,
+    HealthCheck,
+    Status,
+    ActivityNotification {
+        activity_identifier: String,
+        mode: u8,
+        duration: Option<u64>,
+    },
+    Kill,
+    /// Saves the state of the activities and execs a new daemon that restores it,
+    /// the response is the [`ShutdownReport`] of the old daemon
+    Restart,
+    ListActivities,
+    ListLoadedModules,
+    ModuleCommand {
+        module_name: String,
+        args: Vec<String>,
+    },
+    LayoutCommand {
+        args: Vec<String>,
+    },
+    /// Merges `filter` into the log filter, or goes back to the startup one with `reset`,
+    /// the response is the filter in use
+    LogFilter {
+        filter: Option<String>,
+        reset: bool,
+    },
+    /// Writes back the config files that were last applied without errors and reloads them,
+    /// the response is a message with the restored files
+    RollbackConfig,
+    /// Keeps the connection open, the daemon answers with a stream of newline-delimited JSON
+    /// [`Event`](crate::events::Event)s instead of a [`ResponseFrame`]
+    Subscribe(EventFilter),
+}
+
+impl Request {
+    /// Returns `None` for the subcommands that are handled by the client
+    pub fn from_subcommand(command: &SubCommands) -> Option<Self> {
+        let request = match command {
+            SubCommands::Reload => Self::Reload,
+            SubCommands::Config {
+                command: ConfigCommand::Rollback,
+            } => Self::RollbackConfig,
+            SubCommands::Inspector => Self::OpenInspector,
+            SubCommands::HealthCheck => Self::HealthCheck,
+            SubCommands::Status => Self::Status,
+            SubCommands::ActivityNotification {
+                activity_identifier,
+                mode,
+                duration,
+            } => Self::ActivityNotification {
+                activity_identifier: activity_identifier.clone(),
+                mode: *mode,
+                duration: *duration,
+            },
+            SubCommands::Kill => Self::Kill,
+            SubCommands::ListActivities => Self::ListActivities,
+            SubCommands::ListLoadedModules => Self::ListLoadedModules,
+            SubCommands::Module { module_name, args } => Self::ModuleCommand {
+                module_name: module_name.clone(),
+                args: args.clone(),
+            },
+            SubCommands::Layout { args } => Self::LayoutCommand { args: args.clone() },
+            SubCommands::LogFilter { filter, reset } => Self::LogFilter {
+                filter: filter.clone(),
+                reset: *reset,
+            },
+            SubCommands::Subscribe { module, activity } => Self::Subscribe(EventFilter {
+                modules: module.clone(),
+                activities: activity.clone(),
+            }),
+            SubCommands::Daemon { .. }
+            | SubCommands::Restart { .. }
+            | SubCommands::DefaultConfig { .. }
+            | SubCommands::InstallService { .. }
+            | SubCommands::Doctor
+            | SubCommands::Config {
+                command: ConfigCommand::Validate { .. },
+            }
+            | SubCommands::Logs { .. } => return None,
+        };
+        Some(request)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct ActivityInfo {
+    pub module: String,
+    pub activity: String,
+    pub metadata: ActivityMetadataInfo,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct ActivityMetadataInfo {
+    /// Window the activity was assigned to, `None` if it's on the default one
+    pub window: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub origin: ModuleOrigin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct LayoutInfo {
+    pub name: String,
+    pub windows: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct ShutdownStep {
+    /// `layout` or the name of a module
+    pub name: String,
+    pub duration_ms: u64,
+}
+
+impl ShutdownStep {
+    pub fn new(name: &str, duration: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct WindowInfo {
+    pub name: String,
+    /// Connector of the monitor the window is on, `None` if it's not mapped or its gtk window wasn't found
+    pub monitor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct LayoutStatus {
+    pub name: String,
+    pub windows: Vec<WindowInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct ModuleStatus {
+    pub name: String,
+    pub origin: ModuleOrigin,
+    /// Activities of the module that are registered in the layout
+    pub activities: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct ReloadStatus {
+    /// Unix time in seconds
+    pub timestamp: u64,
+    /// Parse errors of the config, the layout and module configs and the stylesheet, empty if the reload succeeded
+    pub errors: Vec<String>,
+    /// Parts of the config that were rejected, the previous ones are still in use
+    /// (`dynisland.ron`, `dynisland.scss`, `module <name>` or `layout <name>`)
+    pub kept: Vec<String>,
+}
+
+impl ReloadStatus {
+    pub fn now(errors: Vec<String>, kept: Vec<String>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            timestamp,
+            errors,
+            kept,
+        }
+    }
+}
+
+/// Response to [`Request::Status`]
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub version: String,
+    pub config_dir: String,
+    /// GSK renderer used by the windows
+    pub renderer: String,
+    /// `None` if no layout manager is loaded
+    pub layout: Option<LayoutStatus>,
+    pub modules: Vec<ModuleStatus>,
+    /// `None` if the config wasn't reloaded since the daemon started
+    pub last_reload: Option<ReloadStatus>,
+}
+
+impl Display for DaemonStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "dynisland {} (pid {})", self.version, self.pid)?;
+        writeln!(f, "uptime: {}", format_duration(self.uptime_secs))?;
+        writeln!(f, "config dir: {}", self.config_dir)?;
+        writeln!(f, "renderer: {}", self.renderer)?;
+        match &self.layout {
+            Some(layout) => {
+                writeln!(f, "layout: {}", layout.name)?;
+                for window in &layout.windows {
+                    let monitor = window.monitor.as_deref().unwrap_or("unknown");
+                    writeln!(f, "  window {}: {monitor}", window.name)?;
+                }
+            }
+            None => writeln!(f, "layout: none")?,
+        }
+        writeln!(f, "modules:")?;
+        for module in &self.modules {
+            let origin = match &module.origin {
+                ModuleOrigin::Embedded => "embedded",
+                ModuleOrigin::Library(path) => path,
+            };
+            writeln!(
+                f,
+                "  {} ({origin}): {} activities",
+                module.name, module.activities
+            )?;
+        }
+        match &self.last_reload {
+            None => write!(f, "last reload: none"),
+            Some(reload) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let ago = format_duration(now.saturating_sub(reload.timestamp));
+                if reload.errors.is_empty() {
+                    write!(f, "last reload: ok, {ago} ago")
+                } else {
+                    write!(f, "last reload: failed, {ago} ago")?;
+                    for error in &reload.errors {
+                        write!(f, "\n  {error}")?;
+                    }
+                    if !reload.kept.is_empty() {
+                        write!(
+                            f,
+                            "\n  the previous config is still in use for: {}",
+                            reload.kept.join(", ")
+                        )?;
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// `1h 2m 3s`
+fn format_duration(secs: u64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes}m {secs}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {secs}s")
+    } else {
+        format!("{secs}s")
+    }
+}
+
+/// How the shutdown went, it's the response to [`Request::Kill`] and [`Request::Restart`]
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+pub struct ShutdownReport {
+    pub deadline_ms: u64,
+    pub total_ms: u64,
+    /// In the order they were run
+    pub steps: Vec<ShutdownStep>,
+}
+
+impl Display for ShutdownReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "shutdown completed in {}ms (deadline {}ms)",
+            self.total_ms, self.deadline_ms
+        )?;
+        for step in &self.steps {
+            write!(f, "\n  {}: {}ms", step.name, step.duration_ms)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a request failed, every kind has its own exit code so scripts can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Unexpected failure, either in the daemon or in the client
+    Internal,
+    /// The client couldn't connect to the daemon
+    NotRunning,
+    /// The daemon speaks a different [`PROTOCOL_VERSION`]
+    ProtocolMismatch,
+    /// The daemon didn't answer in time
+    Timeout,
+    /// The client is running as a user that is not allowed to use the socket
+    PermissionDenied,
+    /// The daemon couldn't start because another one is using the same socket or application id
+    AlreadyRunning,
+    /// The daemon failed or crashed before it was ready
+    StartupFailed,
+    ModuleNotFound,
+    ActivityNotFound,
+    /// The activity identifier is not in the `activity@module` format
+    InvalidActivityIdentifier,
+    InvalidArgument,
+    NoLayout,
+    /// The module or layout manager returned an error from its cli command
+    CommandFailed,
+    /// A part of the config was rejected by the reload, the previous one is still in use
+    ConfigRejected,
+}
+
+impl ErrorKind {
+    /// Exit code used by the cli, 2 is left out because clap uses it for usage errors
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Internal => 1,
+            ErrorKind::NotRunning => 3,
+            ErrorKind::ProtocolMismatch => 4,
+            ErrorKind::Timeout => 5,
+            ErrorKind::PermissionDenied => 6,
+            ErrorKind::AlreadyRunning => 7,
+            ErrorKind::StartupFailed => 8,
+            ErrorKind::ModuleNotFound => 10,
+            ErrorKind::ActivityNotFound => 11,
+            ErrorKind::InvalidActivityIdentifier => 12,
+            ErrorKind::InvalidArgument => 13,
+            ErrorKind::NoLayout => 14,
+            ErrorKind::CommandFailed => 15,
+            ErrorKind::ConfigRejected => 16,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ErrorKind::Internal => "internal error",
+            ErrorKind::NotRunning => "daemon not running",
+            ErrorKind::ProtocolMismatch => "protocol mismatch",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::PermissionDenied => "permission denied",
+            ErrorKind::AlreadyRunning => "already running",
+            ErrorKind::StartupFailed => "startup failed",
+            ErrorKind::ModuleNotFound => "module not found",
+            ErrorKind::ActivityNotFound => "activity not found",
+            ErrorKind::InvalidActivityIdentifier => "invalid activity identifier",
+            ErrorKind::InvalidArgument => "invalid argument",
+            ErrorKind::NoLayout => "no layout loaded",
+            ErrorKind::CommandFailed => "command failed",
+            ErrorKind::ConfigRejected => "config rejected",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum Response {
+    Ok,
+    Message(String),
+    Activities(Vec<ActivityInfo>),
+    Modules(Vec<ModuleInfo>),
+    Status(Box<DaemonStatus>),
+    ModuleOutput { module: String, output: String },
+    LayoutOutput { layout: LayoutInfo, output: String },
+    Shutdown(ShutdownReport),
+    Error { kind: ErrorKind, message: String },
+}
+
+impl Response {
+    pub fn error(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self::Error {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        !matches!(self, Response::Error { .. })
+    }
+
+    /// 0 for every successful response, [`ErrorKind::exit_code`] otherwise
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Response::Error { kind, .. } => kind.exit_code(),
+            _ => 0,
+        }
+    }
+
+    /// Stable JSON representation used by `--json`.
+    ///
+    /// Every object has a `status` field that is either `"ok"` or `"error"`,
+    /// the other fields depend on the command:
+    /// - `reload`, `inspector`, `health-check`, `activity-notification`: nothing else
+    /// - `status`: `daemon`, `{"pid": number, "uptime_secs": number, "version": string, "config_dir": string,
+    ///   "renderer": string, "layout": {"name": string, "windows": [{"name": string, "monitor": string | null}]} | null,
+    ///   "modules": [{"name": string, "origin": origin, "activities": number}],
+    ///   "last_reload": {"timestamp": number, "errors": [string], "kept": [string]} | null}`
+    /// - `kill`: `shutdown`, `{"deadline_ms": number, "total_ms": number, "steps": [{"name": string, "duration_ms": number}]}`,
+    ///   it's missing if the daemon didn't answer (e.g. it was stopped with a signal)
+    /// - `list-activities`: `activities`, a list of
+    ///   `{"module": string, "activity": string, "metadata": {"window": string | null}}`
+    /// - `list-loaded-modules`: `modules`, a list of `{"name": string, "origin": origin}` where
+    ///   origin is `{"type": "embedded"}` or `{"type": "library", "path": string}`
+    /// - `module`: `module` and `output`, the text returned by the module
+    /// - `log-filter`: `message`, the filter in use
+    /// - `config rollback`: `message`, the restored files
+    /// - `layout`: `layout`, `{"name": string, "windows": [string]}`, and `output`
+    /// - errors: `kind`, one of the [`ErrorKind`] variants in snake_case, and `message`
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Response::Ok => json!({ "status": "ok" }),
+            Response::Message(message) => json!({ "status": "ok", "message": message }),
+            Response::Activities(activities) => {
+                json!({ "status": "ok", "activities": activities })
+            }
+            Response::Modules(modules) => json!({ "status": "ok", "modules": modules }),
+            Response::Status(status) => json!({ "status": "ok", "daemon": status }),
+            Response::ModuleOutput { module, output } => {
+                json!({ "status": "ok", "module": module, "output": output })
+            }
+            Response::LayoutOutput { layout, output } => {
+                json!({ "status": "ok", "layout": layout, "output": output })
+            }
+            Response::Shutdown(report) => json!({ "status": "ok", "shutdown": report }),
+            Response::Error { kind, message } => {
+                json!({ "status": "error", "kind": kind, "message": message })
+            }
+        }
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Ok => write!(f, "OK"),
+            Response::Message(message) => write!(f, "{message}"),
+            Response::Activities(list) => {
+                for activity in list {
+                    writeln!(f, "{}@{}", activity.activity, activity.module)?;
+                }
+                Ok(())
+            }
+            Response::Modules(list) => {
+                for module in list {
+                    writeln!(f, "{}", module.name)?;
+                }
+                Ok(())
+            }
+            Response::Status(status) => write!(f, "{status}"),
+            Response::ModuleOutput { output, .. } | Response::LayoutOutput { output, .. } => {
+                write!(f, "{output}")
+            }
+            Response::Shutdown(report) => write!(f, "{report}"),
+            Response::Error { kind, message } => write!(f, "Error ({kind}):\n{message}"),
+        }
+    }
+}
@@ -72,7 +72,7 @@ impl SabiLayoutManager for FallbackLayout {
 
         log::trace!("current config: {:#?}", self.config);
 
-        if self.app.windows().first().is_some() {
+        if !self.app.windows().is_empty() {
             self.update_windows();
             for (window_name, window_config) in self.config.windows.iter() {
                 let window = &self.windows_containers.get(window_name).unwrap().0;
@@ -121,12 +121,7 @@ impl SabiLayoutManager for FallbackLayout {
 
     fn remove_activity(&mut self, activity: &ActivityIdentifier) {
         if let Some(widget) = self.widget_map.remove(activity) {
-            if self
-                .remove_activity_from_container(activity, widget)
-                .is_err()
-            {
-                return;
-            }
+            let _ = self.remove_activity_from_container(activity, widget);
         }
     }
     fn list_activities(&self) -> RVec<ActivityIdentifier> {
@@ -156,16 +151,12 @@ impl SabiLayoutManager for FallbackLayout {
                 .auto_minimize_timeout;
             let timeout = duration.unwrap_or(default_timeout as u64);
             let widget = widget.clone();
-            glib::timeout_add_local_once(
-                Duration::from_millis(timeout.try_into().unwrap()),
-                move || {
-                    if !widget.state_flags().contains(StateFlags::PRELIGHT) && widget.mode() == mode
-                    {
-                        //mouse is not on widget and mode hasn't changed
-                        widget.set_mode(ActivityMode::Compact);
-                    }
-                },
-            );
+            glib::timeout_add_local_once(Duration::from_millis(timeout), move || {
+                if !widget.state_flags().contains(StateFlags::PRELIGHT) && widget.mode() == mode {
+                    //mouse is not on widget and mode hasn't changed
+                    widget.set_mode(ActivityMode::Compact);
+                }
+            });
         }
     }
 }
@@ -405,10 +396,7 @@ impl FallbackLayout {
         let container = &self
             .windows_containers
             .get(&window_name)
-            .expect(&format!(
-                "there should be a default container for {}",
-                window_name
-            ))
+            .unwrap_or_else(|| panic!("there should be a default container for {}", window_name))
             .1;
         container.append(widget);
     }
@@ -4,9 +4,22 @@
 //! and manages the app lifecycle
 //!
 
+pub mod actions;
 pub mod app;
 pub mod cli;
 pub mod config;
+pub mod doctor;
+pub mod error_activity;
+pub mod events;
+pub mod instance;
 pub mod ipc;
 pub mod layout_manager;
+pub mod logging;
 pub mod module_loading;
+pub mod pid_file;
+pub mod readiness;
+pub mod reload;
+pub mod restart;
+pub mod shutdown;
+pub mod status;
+pub mod systemd;
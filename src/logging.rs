@@ -0,0 +1,612 @@
+//! Logger of dynisland, it's [`env_logger`] with a filter that can be changed at runtime
+//! (`dynisland log-filter`), and the reader behind `dynisland logs`.
+//!
+//! Filters use the `RUST_LOG` syntax, without the `/regex` part.
+//! The [`LogConfig`] of the daemon can switch to JSON lines, rotate the log file
+//! or send the records to journald or syslog instead.
+
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    os::unix::{fs::MetadataExt, net::UnixDatagram},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use dynisland_core::abi::log::{self, Level, LevelFilter, Log, Metadata, Record};
+use env_logger::Target;
+use nix::{
+    sys::stat::fstat,
+    unistd::{dup2_stderr, dup2_stdout},
+};
+use serde_json::json;
+
+use crate::config::{LogBackend, LogConfig, LogFormat};
+
+/// Used when `RUST_LOG` is not set
+pub const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Directive {
+    /// `None` applies to every target that doesn't have its own directive
+    target: Option<String>,
+    level: LevelFilter,
+}
+
+/// List of `target=level` directives, a bare level applies to every other target
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    directives: Vec<Directive>,
+}
+
+impl LogFilter {
+    /// Directives in `other` replace the ones with the same target, the others are added
+    pub fn merge(&mut self, other: LogFilter) {
+        for directive in other.directives {
+            match self
+                .directives
+                .iter_mut()
+                .find(|current| current.target == directive.target)
+            {
+                Some(current) => current.level = directive.level,
+                None => self.directives.push(directive),
+            }
+        }
+    }
+
+    fn apply(&self, builder: &mut env_logger::Builder) {
+        for directive in &self.directives {
+            builder.filter(directive.target.as_deref(), directive.level);
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut directives = Vec::new();
+        for part in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            if part.contains('/') {
+                bail!("invalid directive {part:?}, regex filters are not supported");
+            }
+            let directive = match part.split_once('=') {
+                Some((target, level)) => Directive {
+                    target: Some(target.trim().to_string()),
+                    level: LevelFilter::from_str(level.trim())
+                        .with_context(|| format!("invalid level in {part:?}"))?,
+                },
+                // like RUST_LOG, a bare target enables all its levels
+                None => match LevelFilter::from_str(part) {
+                    Ok(level) => Directive {
+                        target: None,
+                        level,
+                    },
+                    Err(_) => Directive {
+                        target: Some(part.to_string()),
+                        level: LevelFilter::Trace,
+                    },
+                },
+            };
+            if directive.target.as_deref() == Some("") {
+                bail!("invalid directive {part:?}, the target is empty");
+            }
+            directives.push(directive);
+        }
+        if directives.is_empty() {
+            bail!("the filter is empty");
+        }
+        Ok(Self { directives })
+    }
+}
+
+impl Display for LogFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let directives: Vec<String> = self
+            .directives
+            .iter()
+            .map(|directive| match &directive.target {
+                Some(target) => format!("{target}={}", directive.level.as_str().to_lowercase()),
+                None => directive.level.as_str().to_lowercase(),
+            })
+            .collect();
+        write!(f, "{}", directives.join(","))
+    }
+}
+
+struct DaemonLogger {
+    /// Rebuilt every time the filter or the output change
+    logger: RwLock<env_logger::Logger>,
+    /// Replaces the output of `logger` with the journald and syslog backends, `logger` is still used to filter
+    sink: RwLock<Option<SocketSink>>,
+    filter: Mutex<LogFilter>,
+    startup_filter: LogFilter,
+    output: Mutex<Output>,
+}
+
+impl Log for DaemonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let logger = self.logger.read().unwrap_or_else(|err| err.into_inner());
+        match self
+            .sink
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_ref()
+        {
+            Some(sink) if logger.matches(record) => sink.send(record),
+            Some(_) => {}
+            None => logger.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.logger
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .flush()
+    }
+}
+
+static LOGGER: OnceLock<DaemonLogger> = OnceLock::new();
+
+/// Format and destination of the env_logger records
+#[derive(Clone)]
+struct Output {
+    format: LogFormat,
+    /// Set when the stderr of the daemon is the log file
+    rotation: Option<Arc<Mutex<Rotation>>>,
+}
+
+fn build_logger(filter: &LogFilter, output: &Output) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    filter.apply(&mut builder);
+    if output.format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    if let Some(rotation) = &output.rotation {
+        builder.target(Target::Pipe(Box::new(RotatingStderr(rotation.clone()))));
+    }
+    builder.build()
+}
+
+/// Installs the logger, the filter comes from `RUST_LOG`
+pub fn init() {
+    let mut filter: LogFilter = "reqwest=warn".parse().expect("valid filter");
+    let env_filter = std::env::var("RUST_LOG").ok();
+    let (startup, invalid) = match env_filter.as_deref().map(LogFilter::from_str) {
+        Some(Ok(env_filter)) => (env_filter, None),
+        Some(Err(err)) => (DEFAULT_FILTER.parse().expect("valid filter"), Some(err)),
+        None => (DEFAULT_FILTER.parse().expect("valid filter"), None),
+    };
+    filter.merge(startup);
+    let output = Output {
+        format: LogFormat::Text,
+        rotation: None,
+    };
+    let logger = LOGGER.get_or_init(|| DaemonLogger {
+        logger: RwLock::new(build_logger(&filter, &output)),
+        sink: RwLock::new(None),
+        filter: Mutex::new(filter.clone()),
+        startup_filter: filter.clone(),
+        output: Mutex::new(output),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.logger.read().unwrap().filter());
+    }
+    if let Some(err) = invalid {
+        log::warn!("ignoring RUST_LOG: {err:#}");
+    }
+}
+
+/// Applies the log config of the daemon. The `File` backend writes to stderr, which is `log_path`
+/// only when the daemon was started detached (or stderr was redirected there), the file is only
+/// rotated while running in that case
+pub fn configure(config: &LogConfig, log_path: &Path) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let filter = logger.filter.lock().unwrap();
+    let mut output = logger.output.lock().unwrap();
+    output.format = config.format;
+    output.rotation = (config.backend == LogBackend::File
+        && config.max_size > 0
+        && is_stderr(log_path))
+    .then(|| {
+        Arc::new(Mutex::new(Rotation {
+            path: log_path.to_path_buf(),
+            max_size: config.max_size,
+            max_files: config.max_files,
+        }))
+    });
+    let sink = match config.backend {
+        LogBackend::File => None,
+        LogBackend::Journald | LogBackend::Syslog => match SocketSink::new(config) {
+            Ok(sink) => Some(sink),
+            Err(err) => {
+                log::warn!(
+                    "failed to start the {:?} log backend, using stderr: {err}",
+                    config.backend
+                );
+                None
+            }
+        },
+    };
+    let new_logger = build_logger(&filter, &output);
+    *logger.logger.write().unwrap_or_else(|err| err.into_inner()) = new_logger;
+    *logger.sink.write().unwrap_or_else(|err| err.into_inner()) = sink;
+}
+
+/// Filter in use
+pub fn filter() -> LogFilter {
+    LOGGER
+        .get()
+        .map(|logger| logger.filter.lock().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Merges `filter` into the one in use and returns the result
+pub fn update_filter(filter: LogFilter) -> LogFilter {
+    let Some(logger) = LOGGER.get() else {
+        return LogFilter::default();
+    };
+    let mut current = logger.filter.lock().unwrap();
+    current.merge(filter);
+    set_filter(logger, &current);
+    current.clone()
+}
+
+/// Goes back to the filter the daemon was started with
+pub fn reset_filter() -> LogFilter {
+    let Some(logger) = LOGGER.get() else {
+        return LogFilter::default();
+    };
+    let mut current = logger.filter.lock().unwrap();
+    *current = logger.startup_filter.clone();
+    set_filter(logger, &current);
+    current.clone()
+}
+
+fn set_filter(logger: &DaemonLogger, filter: &LogFilter) {
+    let new_logger = build_logger(filter, &logger.output.lock().unwrap());
+    log::set_max_level(new_logger.filter());
+    *logger.logger.write().unwrap_or_else(|err| err.into_inner()) = new_logger;
+    log::info!("log filter set to {filter}");
+}
+
+/// Opens the log file for a detached daemon, with `rotate` the previous log is rotated first
+pub fn open_log_file(path: &Path, max_files: u32, rotate: bool) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if rotate {
+        rotate_files(path, max_files)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Moves `path` to `path.1`, `path.1` to `path.2` and so on, the oldest one is overwritten
+fn rotate_files(path: &Path, max_files: u32) -> io::Result<()> {
+    let numbered = |n: u32| PathBuf::from(format!("{}.{n}", path.display()));
+    let result = if max_files == 0 {
+        std::fs::remove_file(path)
+    } else {
+        for n in (1..max_files).rev() {
+            let _ = std::fs::rename(numbered(n), numbered(n + 1));
+        }
+        std::fs::rename(path, numbered(1))
+    };
+    match result {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn is_stderr(path: &Path) -> bool {
+    match (fstat(io::stderr()), std::fs::metadata(path)) {
+        (Ok(stderr), Ok(file)) => stderr.st_dev == file.dev() && stderr.st_ino == file.ino(),
+        _ => false,
+    }
+}
+
+struct Rotation {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl Rotation {
+    /// The new file also replaces stdout and stderr, so the output of gtk and panics follow it
+    fn rotate_if_full(&self) -> io::Result<()> {
+        if (fstat(io::stderr())?.st_size as u64) < self.max_size {
+            return Ok(());
+        }
+        let file = open_log_file(&self.path, self.max_files, true)?;
+        dup2_stdout(&file)?;
+        dup2_stderr(&file)?;
+        Ok(())
+    }
+}
+
+/// Writes to stderr and rotates the log file after the record that fills it
+struct RotatingStderr(Arc<Mutex<Rotation>>);
+
+impl Write for RotatingStderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write(buf)
+    }
+
+    /// env_logger flushes after every record
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .rotate_if_full()
+    }
+}
+
+/// Sends the records to journald or syslog, they fall back to stderr if the socket doesn't work
+struct SocketSink {
+    socket: UnixDatagram,
+    path: PathBuf,
+    backend: LogBackend,
+}
+
+impl SocketSink {
+    fn new(config: &LogConfig) -> io::Result<Self> {
+        let default_path = match config.backend {
+            LogBackend::Syslog => "/dev/log",
+            _ => "/run/systemd/journal/socket",
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            path: config
+                .socket
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(default_path)),
+            backend: config.backend,
+        })
+    }
+
+    fn send(&self, record: &Record) {
+        let message = record.args().to_string();
+        let payload = match self.backend {
+            LogBackend::Syslog => syslog_message(record, &message),
+            _ => journald_message(record, &message),
+        };
+        if let Err(err) = self.socket.send_to(&payload, &self.path) {
+            eprintln!(
+                "[{} {}] {message} (failed to send it to {}: {err})",
+                record.level(),
+                record.target(),
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Syslog severity
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Native journald protocol, values with newlines are length-prefixed
+fn journald_message(record: &Record, message: &str) -> Vec<u8> {
+    let mut fields = vec![
+        ("PRIORITY", priority(record.level()).to_string()),
+        ("SYSLOG_IDENTIFIER", "dynisland".to_string()),
+        ("SYSLOG_PID", std::process::id().to_string()),
+        ("TARGET", record.target().to_string()),
+        ("MESSAGE", message.to_string()),
+    ];
+    if let Some(file) = record.file() {
+        fields.push(("CODE_FILE", file.to_string()));
+    }
+    if let Some(line) = record.line() {
+        fields.push(("CODE_LINE", line.to_string()));
+    }
+    let mut payload = Vec::new();
+    for (key, value) in fields {
+        payload.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            payload.push(b'\n');
+            payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            payload.push(b'=');
+        }
+        payload.extend_from_slice(value.as_bytes());
+        payload.push(b'\n');
+    }
+    payload
+}
+
+/// RFC 3164 message with the `user` facility, the timestamp is added by the syslog daemon
+fn syslog_message(record: &Record, message: &str) -> Vec<u8> {
+    format!(
+        "<{}>dynisland[{}]: {}: {message}",
+        8 + priority(record.level()),
+        std::process::id(),
+        record.target()
+    )
+    .into_bytes()
+}
+
+/// Level of a line written by the logger, `None` for the continuation lines of a multi-line message.
+///
+/// The lines look like `[2024-01-01T00:00:00Z INFO  dynisland::app] message`, or are JSON objects
+pub fn line_level(line: &str) -> Option<Level> {
+    if line.starts_with('{') {
+        let record: serde_json::Value = serde_json::from_str(line).ok()?;
+        return Level::from_str(record.get("level")?.as_str()?).ok();
+    }
+    let header = line.strip_prefix('[')?.split(']').next()?;
+    header
+        .split_whitespace()
+        .find_map(|word| Level::from_str(word).ok())
+}
+
+/// Prints the log file, with `follow` it keeps printing the new lines like `tail -f`,
+/// also after the file is truncated or replaced
+pub fn print_log(path: &Path, min_level: Option<Level>, follow: bool) -> Result<()> {
+    let open = || -> Result<(BufReader<File>, u64)> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let inode = file.metadata()?.ino();
+        Ok((BufReader::new(file), inode))
+    };
+    let (mut reader, mut inode) = open()?;
+    let mut stdout = io::stdout().lock();
+    // continuation lines are shown if the line they belong to is
+    let mut show = true;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            if !follow {
+                return Ok(());
+            }
+            // a partial line is read again once it's complete
+            let position = reader.stream_position()? - line.len() as u64;
+            reader.seek(SeekFrom::Start(position))?;
+            thread::sleep(Duration::from_millis(200));
+            match std::fs::metadata(path) {
+                Ok(metadata) if metadata.ino() != inode || metadata.len() < position => {
+                    (reader, inode) = open()?;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        if let Some(level) = line_level(&line) {
+            show = min_level.is_none_or(|min_level| level <= min_level);
+        }
+        if show {
+            if let Err(err) = stdout.write_all(line.as_bytes()) {
+                // e.g. piped to `head`
+                if err.kind() == io::ErrorKind::BrokenPipe {
+                    return Ok(());
+                }
+                return Err(err.into());
+            }
+            if follow {
+                stdout.flush()?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receiver(name: &str) -> (UnixDatagram, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("dynisland-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (socket, path)
+    }
+
+    fn sink(backend: LogBackend, path: &Path) -> SocketSink {
+        SocketSink::new(&LogConfig {
+            backend,
+            socket: Some(path.to_path_buf()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn journald_sink() {
+        let (journal, path) = receiver("journald");
+        let sink = sink(LogBackend::Journald, &path);
+        let mut buf = vec![0u8; 4096];
+
+        sink.send(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("dynisland::app")
+                .args(format_args!("hello"))
+                .build(),
+        );
+        let len = journal.recv(&mut buf).unwrap();
+        let payload = String::from_utf8_lossy(&buf[..len]);
+        assert!(payload.contains("PRIORITY=4\n"), "{payload}");
+        assert!(payload.contains("SYSLOG_IDENTIFIER=dynisland\n"));
+        assert!(payload.contains("TARGET=dynisland::app\n"));
+        assert!(payload.contains("MESSAGE=hello\n"));
+
+        // a value with newlines is length-prefixed
+        sink.send(
+            &Record::builder()
+                .level(Level::Error)
+                .args(format_args!("two\nlines"))
+                .build(),
+        );
+        let len = journal.recv(&mut buf).unwrap();
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert!(buf[..len]
+            .windows(expected.len())
+            .any(|window| window == expected));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn syslog_sink() {
+        let (syslog, path) = receiver("syslog");
+        let mut buf = vec![0u8; 4096];
+
+        sink(LogBackend::Syslog, &path).send(
+            &Record::builder()
+                .level(Level::Info)
+                .target("dynisland::app")
+                .args(format_args!("hello"))
+                .build(),
+        );
+        let len = syslog.recv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..len]),
+            format!(
+                "<14>dynisland[{}]: dynisland::app: hello",
+                std::process::id()
+            )
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
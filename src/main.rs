@@ -1,45 +1,55 @@
 use std::{
-    io::ErrorKind,
-    os::{fd::AsFd, unix::net::UnixStream},
-    path::Path,
+    fs::File,
+    io,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::{fs::MetadataExt, net::UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::ExitCode,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
 use clap::Parser;
 use dynisland::{
     app::App,
-    cli::{
-        Cli,
-        SubCommands::{self, *},
+    cli::{Cli, ConfigCommand, SubCommands::*},
+    config::{self, merge::ConfigMerge, LogBackend, LogConfig},
+    doctor::{self, Severity},
+    events::EventFilter,
+    instance::Instance,
+    ipc::{
+        self,
+        codec::{FrameCodec, FrameError},
+        protocol::{ErrorKind, NoHandshake, ProtocolMismatch, Request, Response},
     },
-    config, ipc,
+    logging,
+    pid_file::{self, AlreadyRunning, PidFile},
+    readiness::Readiness,
+    restart::{self, SavedState},
+    systemd,
 };
 use dynisland_core::abi::{abi_stable, log, module::UIServerCommand};
-use env_logger::Env;
 use log::Level;
-use nix::unistd::Pid;
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::signal::{self, Signal},
+};
 
 // [ ] TODO remove some unnecessary arc and mutexes
 // [ ] TODO remove some unwraps and handle errors better
 // [x] TODO add docs
 // [x] TODO remove some unnecessary clones
 
-// [ ] TODO detect nvidia gpu and display warning (if dynisland uses too much ram, use GSK_RENDERER=vulkan or GSK_RENDERER=gl)
-
 // FIXME Gsk-WARNING **: 13:09:06.082: Clipping is broken, everything is clipped, but we didn't early-exit.
 // maybe it's in ScrollingLabel
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     system_mimalloc::use_mimalloc!();
-    env_logger::Builder::new()
-        // .filter_module("dynisland", log::LevelFilter::Debug)
-        // .filter_module("dynisland_core", log::LevelFilter::Debug)
-        // .filter_module("dynisland_modules", log::LevelFilter::Debug)
-        .filter(Some("reqwest"), log::LevelFilter::Warn)
-        .parse_env(Env::default().default_filter_or(Level::Info.as_str()))
-        .init();
+    logging::init();
 
     let cli = Cli::parse();
     let config_dir = cli
@@ -47,39 +57,32 @@ fn main() -> Result<()> {
         .clone()
         .unwrap_or(config::get_default_config_path());
     let config = config::get_config(&config_dir);
+    let instance = Instance::new(cli.instance.clone())?;
+    let codec = config.ipc.codec();
     log::debug!("{cli:?}");
     match cli.command {
-        Daemon { no_daemonize } => {
-            let runtime_dir = config.get_runtime_dir();
-            if let Ok(stream) = UnixStream::connect(runtime_dir.join("dynisland.sock")) {
-                match ipc::send_recv_message(stream, &HealthCheck) {
-                    Ok(_) => {
-                        //app is already runnig
-                        log::error!("Application is already running");
-                    }
-                    Err(_) => {
-                        log::error!("Error sending HealthCheck");
-                    }
-                };
-                return Ok(());
-            } else {
-                let _ = std::fs::remove_file(runtime_dir.join("dynisland.sock"));
-            }
-            let pid = if !no_daemonize {
-                let log_path = runtime_dir.join("dynisland.log");
-                detach(&log_path)?
-            } else {
-                Pid::from_raw(std::process::id() as i32)
-            };
-            //init GTK
-            gtk::init().with_context(|| "failed to init gtk")?;
-            let app = App::default();
-            log::info!("pid: {pid}");
-            app.run(&config_dir)?;
+        Daemon {
+            no_daemonize,
+            restore_state,
+        } => {
+            let runtime_dir = instance.runtime_dir(&config);
+            return start_daemon(
+                &config_dir,
+                &runtime_dir,
+                &instance,
+                no_daemonize,
+                restore_state,
+                &config.log,
+                cli.json,
+            );
         }
         Reload
+        | Config {
+            command: ConfigCommand::Rollback,
+        }
         | Inspector
         | HealthCheck
+        | Status
         | ActivityNotification {
             activity_identifier: _,
             mode: _,
@@ -90,166 +93,686 @@ fn main() -> Result<()> {
             args: _,
         }
         | Layout { args: _ }
+        | LogFilter {
+            filter: _,
+            reset: _,
+        }
         | ListActivities
         | ListLoadedModules => {
-            let socket_path = config.get_runtime_dir().join("dynisland.sock");
-            match UnixStream::connect(socket_path.clone()) {
-                Ok(stream) => {
-                    if let Some(response) = ipc::send_recv_message(stream, &cli.command)? {
-                        println!("Response: \n{response}");
-                    }
-                    // if cli.command == HealthCheck {
-                    //     println!("OK");
-                    // }
-                }
-                Err(err) => {
-                    log::error!("Error opening dynisland socket: {err}");
-                    if matches!(err.kind(), ErrorKind::ConnectionRefused) {
-                        log::info!("Connection refused, deleting old socket file");
-                        std::fs::remove_file(socket_path.clone())?;
-                    }
-                }
+            let socket_path = instance.runtime_dir(&config).join("dynisland.sock");
+            let request = Request::from_subcommand(&cli.command)
+                .expect("command should be handled by the daemon");
+            let response = match connect(&socket_path) {
+                Ok(stream) => match ipc::send_recv_message(stream, &request, &codec) {
+                    Ok(Some(response)) => response,
+                    Ok(None) => Response::error(
+                        ErrorKind::Internal,
+                        "the daemon closed the connection without answering",
+                    ),
+                    Err(err) => client_error(err),
+                },
+                Err(response) => response,
             };
+            print_response(&response, cli.json);
+            return Ok(response.exit_code().into());
         }
-        Kill => {
-            let socket_path = config.get_runtime_dir().join("dynisland.sock");
-            match UnixStream::connect(socket_path.clone()) {
-                Ok(stream) => {
-                    let response = ipc::send_recv_message(stream, &cli.command)?;
-                    println!("Kill message sent");
-                    let has_responded = if let Some(response) = response {
-                        println!("Response: \n{response}");
-                        true
-                    } else {
-                        false
-                    };
-                    let mut tries = 0;
-                    while socket_path.exists() && tries < 10 {
-                        thread::sleep(Duration::from_millis(500));
-                        if !has_responded {
-                            print!(".");
-                        }
-                        tries += 1;
-                    }
-                    println!();
-                    if tries == 10 {
-                        log::error!("Failed to stop the old instance, manual kill needed");
-                    } else if !has_responded {
-                        println!("OK");
-                    }
-                }
-                Err(err) => {
-                    if matches!(err.kind(), ErrorKind::ConnectionRefused) {
-                        log::info!("Connection refused, deleting old socket file");
-                        std::fs::remove_file(socket_path.clone())?;
-                    } else {
-                        log::warn!(
-                            "Error connecting to socket, app is probably not running: {err}"
-                        );
-                    }
-                }
+        Subscribe { module, activity } => {
+            let socket_path = instance.runtime_dir(&config).join("dynisland.sock");
+            let filter = EventFilter {
+                modules: module,
+                activities: activity,
+            };
+            let result = match connect(&socket_path) {
+                Ok(stream) => ipc::subscribe(stream, filter, &codec).map_err(client_error),
+                Err(response) => Err(response),
             };
+            if let Err(response) = result {
+                print_response(&response, cli.json);
+                return Ok(response.exit_code().into());
+            }
+        }
+        Kill => {
+            let response = stop_daemon(&instance.runtime_dir(&config), &codec, cli.json);
+            print_response(&response, cli.json);
+            return Ok(response.exit_code().into());
         }
         Restart { no_daemonize } => {
-            let socket_path = config.get_runtime_dir().join("dynisland.sock");
-            match UnixStream::connect(socket_path.clone()) {
-                Ok(stream) => {
-                    let response = ipc::send_recv_message(stream, &SubCommands::Kill)?;
-                    let has_responded = if let Some(response) = response {
-                        log::info!("Response: \n{response}");
-                        true
-                    } else {
-                        false
-                    };
-                    log::info!("Waiting for daemon to die");
-                    let mut tries = 0;
-                    while socket_path.exists() && tries < 10 {
-                        thread::sleep(Duration::from_millis(500));
-                        if !has_responded {
-                            print!(".");
+            let runtime_dir = instance.runtime_dir(&config);
+            // a daemon in the foreground has to be started again from this terminal
+            if !no_daemonize && pid_file::is_locked(&runtime_dir) {
+                match restart_daemon(&runtime_dir, &config.log.path(&runtime_dir), &codec) {
+                    Some(response) => {
+                        if cli.json || !response.is_ok() {
+                            print_response(&response, cli.json);
                         }
-                        tries += 1;
-                    }
-                    println!();
-                    if tries == 10 {
-                        log::error!("failed to stop the old instance, manual kill needed");
-                    } else if !has_responded {
-                        println!("OK");
+                        return Ok(response.exit_code().into());
                     }
+                    None => log::info!("the daemon didn't restart itself, stopping it"),
                 }
-                Err(err) => {
-                    log::error!("Error opening dynisland socket: {err}");
-                    if matches!(err.kind(), ErrorKind::ConnectionRefused) {
-                        log::info!("Connection refused, trying to delete old socket file");
-                        std::fs::remove_file(socket_path.clone())?;
-                    }
+            }
+            match stop_daemon(&runtime_dir, &codec, cli.json) {
+                Response::Ok | Response::Shutdown(_) => log::info!("stopped the old daemon"),
+                Response::Error {
+                    kind: ErrorKind::NotRunning,
+                    ..
+                } => log::info!("the daemon was not running"),
+                response => {
+                    print_response(&response, cli.json);
+                    return Ok(response.exit_code().into());
                 }
-            };
-
-            let pid = if !no_daemonize {
-                let path = config.get_runtime_dir().join("dynisland.log");
-                detach(&path)?
-            } else {
-                Pid::from_raw(std::process::id() as i32)
-            };
-            //init GTK
-            gtk::init().with_context(|| "failed to init gtk")?;
-            let app = App::default();
-            log::info!("pid: {pid}");
-            app.run(&config_dir)?;
+            }
+            return start_daemon(
+                &config_dir,
+                &runtime_dir,
+                &instance,
+                no_daemonize,
+                None,
+                &config.log,
+                cli.json,
+            );
         }
         DefaultConfig {
             replace_current_config,
+            dry_run,
         } => {
             gtk::init().with_context(|| "failed to init gtk")?;
             let mut app = App {
-                config_dir,
+                config_dir: config_dir.clone(),
                 ..Default::default()
             };
             let (abi_app_send, _abi_app_recv) =
                 abi_stable::external_types::crossbeam_channel::unbounded::<UIServerCommand>();
             app.app_send = Some(abi_app_send);
-            let (_conf, conf_str) = app.get_default_config();
-            println!("{conf_str}");
+            let (conf, conf_str) = app.get_default_config();
             if replace_current_config {
-                todo!();
+                return replace_config(&config_dir, &conf, dry_run, cli.json);
+            }
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&conf)?);
+            } else {
+                println!("{conf_str}");
+            }
+        }
+        Logs { follow, level } => {
+            if config.log.backend != LogBackend::File {
+                log::warn!(
+                    "the log backend is {:?}, the log file may be empty",
+                    config.log.backend
+                );
+            }
+            let path = config.log.path(&instance.runtime_dir(&config));
+            let level = level.map(|level| level.parse::<Level>()).transpose()?;
+            logging::print_log(&path, level, follow)?;
+        }
+        Doctor => {
+            let checks = doctor::run(&config_dir, &instance.runtime_dir(&config));
+            let failed = checks.iter().any(|check| check.severity == Severity::Error);
+            if cli.json {
+                let status = if failed { "error" } else { "ok" };
+                println!(
+                    "{}",
+                    serde_json::json!({"status": status, "checks": checks})
+                );
+            } else {
+                for check in &checks {
+                    println!("{check}");
+                }
+            }
+            if failed {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Config {
+            command: ConfigCommand::Validate { path, no_modules },
+        } => {
+            let path = match path {
+                Some(path) if path.is_dir() => path.join("dynisland.ron"),
+                Some(path) => path,
+                None => config_dir.join("dynisland.ron"),
+            };
+            let mut app = App {
+                config_dir: config_dir.clone(),
+                ..Default::default()
+            };
+            let (abi_app_send, _abi_app_recv) =
+                abi_stable::external_types::crossbeam_channel::unbounded::<UIServerCommand>();
+            app.app_send = Some(abi_app_send);
+            let diagnostics = app.validate_config(&path, !no_modules);
+            let failed = diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.severity == Severity::Error);
+            if cli.json {
+                let status = if failed { "error" } else { "ok" };
+                println!(
+                    "{}",
+                    serde_json::json!({"status": status, "diagnostics": diagnostics})
+                );
+            } else if diagnostics.is_empty() {
+                println!("{}: ok", path.display());
+            } else {
+                for diagnostic in &diagnostics {
+                    println!("{diagnostic}");
+                }
+            }
+            if failed {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        InstallService {
+            output,
+            force,
+            print,
+        } => {
+            let executable =
+                std::env::current_exe().with_context(|| "failed to get the executable path")?;
+            let config_path = cli
+                .config_path
+                .as_deref()
+                .map(std::path::absolute)
+                .transpose()?;
+            let unit = systemd::unit(&executable, config_path.as_deref(), &instance);
+            if print {
+                print!("{unit}");
+                return Ok(ExitCode::SUCCESS);
+            }
+            let path = output.unwrap_or_else(|| systemd::default_unit_path(&instance));
+            systemd::install_service(&unit, &path, force)?;
+            let unit_name = systemd::unit_name(&instance);
+            if cli.json {
+                println!(
+                    "{}",
+                    serde_json::json!({"status": "ok", "path": path, "unit": unit_name})
+                );
+            } else {
+                println!("Unit written to {}", path.display());
+                println!("Enable it with: systemctl --user daemon-reload && systemctl --user enable --now {unit_name}");
             }
         }
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-fn detach(log_file_path: &Path) -> Result<Pid> {
-    std::fs::create_dir_all(log_file_path.parent().expect("invalid log path"))?;
+/// Connects to the daemon socket, deleting it if nobody is listening
+fn connect(socket_path: &Path) -> Result<UnixStream, Response> {
+    UnixStream::connect(socket_path).map_err(|err| {
+        log::debug!("Error opening dynisland socket: {err}");
+        if matches!(err.kind(), io::ErrorKind::ConnectionRefused) {
+            log::info!("Connection refused, deleting old socket file");
+            let _ = std::fs::remove_file(socket_path);
+        }
+        Response::error(
+            ErrorKind::NotRunning,
+            format!("cannot connect to the daemon, is it running? ({err})"),
+        )
+    })
+}
+
+/// Turns a failure on the client side into an error response, so it gets an exit code
+fn client_error(err: anyhow::Error) -> Response {
+    if err.downcast_ref::<ProtocolMismatch>().is_some()
+        || err.downcast_ref::<NoHandshake>().is_some()
+    {
+        return Response::error(ErrorKind::ProtocolMismatch, err.to_string());
+    }
+    let timed_out = err.downcast_ref::<io::Error>().is_some_and(|err| {
+        matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    }) || matches!(err.downcast_ref::<FrameError>(), Some(FrameError::Timeout));
+    if timed_out {
+        return Response::error(ErrorKind::Timeout, "the daemon didn't answer in time");
+    }
+    Response::error(ErrorKind::Internal, format!("{err:#}"))
+}
+
+/// Errors go to stderr unless `json` is set, so scripts can still parse stdout
+fn print_response(response: &Response, json: bool) {
+    if json {
+        println!("{}", response.to_json());
+    } else if response.is_ok() {
+        println!("Response: \n{response}");
+    } else {
+        eprintln!("{response}");
+    }
+}
+
+/// Adds the options missing from `dynisland.ron`, with `dry_run` the changes are only printed
+fn replace_config(
+    config_dir: &Path,
+    default: &config::Config,
+    dry_run: bool,
+    json: bool,
+) -> Result<ExitCode> {
+    let merge = ConfigMerge::new(&config_dir.join("dynisland.ron"), default)?;
+    let write = !dry_run && !merge.is_empty() && merge.in_place;
+    let backup = if write { merge.write()? } else { None };
+    // without `in_place` the diff is the only way to apply the changes
+    let show_diff = dry_run || !merge.in_place;
+    let exit_code = if write || dry_run || merge.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    };
+    if json {
+        let mut output = serde_json::to_value(&merge)?;
+        output["written"] = write.into();
+        output["backup"] = backup.map(|path| path.display().to_string()).into();
+        if show_diff {
+            output["diff"] = merge.diff().into();
+        }
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(exit_code);
+    }
+    if merge.is_empty() {
+        println!("{} already has every option", merge.path.display());
+        return Ok(exit_code);
+    }
+    if show_diff {
+        print!("{}", merge.diff());
+    }
+    for key in &merge.added {
+        println!("added {key}");
+    }
+    for key in &merge.unknown {
+        println!("kept {key}, it's not a dynisland option");
+    }
+    if let Some(backup) = backup {
+        println!(
+            "updated {}, the previous config is in {}",
+            merge.path.display(),
+            backup.display()
+        );
+    } else if write {
+        println!("created {}", merge.path.display());
+    } else if !dry_run {
+        eprintln!(
+            "{} was not changed, the options can't be added without rewriting it and losing its comments, apply the diff by hand",
+            merge.path.display()
+        );
+    }
+    Ok(exit_code)
+}
+
+/// Runs the daemon, in a detached process unless `no_daemonize` is set.
+///
+/// When it detaches, this process waits until the daemon is ready and exits with the startup outcome.
+/// `restore_state` is the state saved by a daemon that restarted itself
+fn start_daemon(
+    config_dir: &Path,
+    runtime_dir: &Path,
+    instance: &Instance,
+    no_daemonize: bool,
+    restore_state: Option<PathBuf>,
+    log_config: &LogConfig,
+    json: bool,
+) -> Result<ExitCode> {
+    if !no_daemonize && systemd::is_supervised() {
+        log::warn!("NOTIFY_SOCKET is set but the daemon is going to fork, use --no-daemonize when running as a Type=notify service");
+    }
+    let log_path = log_config.path(runtime_dir);
+    let readiness = if !no_daemonize {
+        // opened here so that an error is reported to the user, the log of a running daemon is not rotated
+        let log_file = logging::open_log_file(
+            &log_path,
+            log_config.max_files,
+            !pid_file::is_locked(runtime_dir),
+        )
+        .with_context(|| format!("failed to open the log file {}", log_path.display()))?;
+        match detach(log_file, &log_path)? {
+            Detached::Parent(outcome) => {
+                if json || !outcome.is_ok() {
+                    print_response(&outcome, json);
+                }
+                return Ok(outcome.exit_code().into());
+            }
+            Detached::Daemon(pipe) => Readiness::with_pipe(pipe),
+        }
+    } else {
+        Readiness::default()
+    };
+    logging::configure(log_config, &log_path);
+
+    // a panic before the daemon is ready (e.g. in the layout manager) is a startup failure
+    let panic_readiness = readiness.clone();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        panic_readiness.fail(ErrorKind::StartupFailed, format!("dynisland {info}"));
+    }));
+
+    // the lock is held until the daemon exits, it's what tells if a daemon is running
+    let pid_file = match PidFile::acquire(runtime_dir) {
+        Ok(pid_file) => pid_file,
+        Err(err) => {
+            let kind = if err.downcast_ref::<AlreadyRunning>().is_some() {
+                ErrorKind::AlreadyRunning
+            } else {
+                ErrorKind::StartupFailed
+            };
+            readiness.fail(kind, format!("{err:#}"));
+            return Ok(kind.exit_code().into());
+        }
+    };
+
+    //init GTK
+    if let Err(err) = gtk::init() {
+        readiness.fail(
+            ErrorKind::StartupFailed,
+            format!("failed to init gtk: {err}"),
+        );
+        return Err(err).with_context(|| "failed to init gtk");
+    }
+    // a state that can't be restored only loses the modes of the activities
+    let restore_state = restore_state.and_then(|path| {
+        SavedState::load(&path)
+            .inspect_err(|err| log::warn!("not restoring the state: {err:#}"))
+            .ok()
+    });
+    if doctor::is_nvidia() && std::env::var_os("GSK_RENDERER").is_none() {
+        log::warn!("NVIDIA driver detected, if dynisland uses too much memory set GSK_RENDERER=vulkan or GSK_RENDERER=gl");
+    }
+    let app = App {
+        instance: instance.clone(),
+        readiness: readiness.clone(),
+        restore_state,
+        runtime_dir: runtime_dir.to_path_buf(),
+        ..Default::default()
+    };
+    let restart_requested = app.restart_requested.clone();
+    log::info!("pid: {}", std::process::id());
+    let result = app.run(config_dir);
+    pid_file.remove();
+    if result.is_ok() && restart_requested.get() {
+        let state_path = runtime_dir.join(restart::STATE_FILE_NAME);
+        return Err(restart::exec(config_dir, instance, &state_path));
+    }
+    if let Err(err) = &result {
+        readiness.fail(ErrorKind::StartupFailed, format!("{err:#}"));
+    }
+    match readiness.outcome() {
+        Some(outcome) if !outcome.is_ok() => Ok(outcome.exit_code().into()),
+        _ => result.map(|_| ExitCode::SUCCESS),
+    }
+}
+
+/// How long the daemon has to exit after the kill message, SIGTERM and SIGKILL
+const STOP_TIMEOUTS: [Duration; 3] = [
+    Duration::from_secs(5),
+    Duration::from_secs(3),
+    Duration::from_secs(2),
+];
+
+/// Stops the daemon through the socket, if it doesn't answer or doesn't exit in time
+/// it gets SIGTERM and then SIGKILL. The stale socket and PID file are removed.
+///
+/// A daemon without the PID file is older than the handshake, it's asked to stop with its own protocol
+fn stop_daemon(runtime_dir: &Path, codec: &FrameCodec, json: bool) -> Response {
+    let socket_path = runtime_dir.join("dynisland.sock");
+    let has_pid_file = pid_file::is_locked(runtime_dir);
+    let stream = connect(&socket_path);
+    let pid = match &stream {
+        Ok(stream) if !has_pid_file => ipc::peer_pid(stream),
+        _ => pid_file::read_pid(runtime_dir).filter(|_| has_pid_file),
+    };
+    let is_stopped = || match pid {
+        _ if has_pid_file => !pid_file::is_locked(runtime_dir),
+        Some(pid) => signal::kill(pid, None) == Err(Errno::ESRCH),
+        None => UnixStream::connect(&socket_path).is_err(),
+    };
+    let stopped = |response: Response| {
+        pid_file::remove_stale(runtime_dir);
+        let _ = std::fs::remove_file(&socket_path);
+        response
+    };
+
+    match stream {
+        Ok(stream) if !has_pid_file => {
+            log::info!("the daemon has no PID file, stopping it as an older version");
+            match ipc::send_legacy_kill(stream, codec) {
+                Ok(()) => {
+                    if !json {
+                        println!("Kill message sent");
+                    }
+                    if wait_until(STOP_TIMEOUTS[0], is_stopped) {
+                        return stopped(Response::Ok);
+                    }
+                    log::warn!("the daemon didn't stop after the kill message");
+                }
+                Err(err) => log::warn!("failed to send the kill message: {err:#}"),
+            }
+        }
+        Ok(stream) => match ipc::send_recv_message(stream, &Request::Kill, codec) {
+            // signals wouldn't work either
+            Ok(Some(
+                response @ Response::Error {
+                    kind: ErrorKind::PermissionDenied,
+                    ..
+                },
+            )) => return response,
+            // the shutdown report, or the error if the shutdown didn't finish in time
+            Ok(response) => {
+                if !json {
+                    println!("Kill message sent");
+                }
+                if wait_until(STOP_TIMEOUTS[0], is_stopped) {
+                    // the daemon is gone, also when its shutdown went past the deadline
+                    return stopped(match response {
+                        Some(Response::Error {
+                            kind: ErrorKind::Timeout,
+                            message,
+                        }) => {
+                            log::warn!("the daemon exited before its shutdown finished: {message}");
+                            Response::Ok
+                        }
+                        response => response.unwrap_or(Response::Ok),
+                    });
+                }
+                log::warn!("the daemon didn't stop after the kill message");
+            }
+            Err(err) => log::warn!("failed to send the kill message: {err:#}"),
+        },
+        Err(response) if !has_pid_file => return response,
+        Err(_) => log::warn!("the daemon is running but its socket doesn't work"),
+    }
+
+    let Some(pid) = pid else {
+        return Response::error(
+            ErrorKind::Timeout,
+            "failed to stop the daemon and its pid is unknown, manual kill needed",
+        );
+    };
+    for (signal, timeout) in [
+        (Signal::SIGTERM, STOP_TIMEOUTS[1]),
+        (Signal::SIGKILL, STOP_TIMEOUTS[2]),
+    ] {
+        log::warn!("sending {signal} to the daemon (pid {pid})");
+        match signal::kill(pid, signal) {
+            // it exited in the meantime
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(err) => {
+                let kind = if err == Errno::EPERM {
+                    ErrorKind::PermissionDenied
+                } else {
+                    ErrorKind::Internal
+                };
+                return Response::error(kind, format!("failed to send {signal} to {pid}: {err}"));
+            }
+        }
+        if wait_until(timeout, is_stopped) {
+            return stopped(Response::Ok);
+        }
+    }
+    Response::error(
+        ErrorKind::Timeout,
+        format!("the daemon (pid {pid}) didn't stop even after SIGKILL"),
+    )
+}
+
+/// How long `restart` waits for the new daemon to be ready
+const RESTART_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Asks the daemon to restart itself keeping the state of its activities, and waits until the new one is ready.
+/// Returns `None` if the daemon can't do it (it's older than this client) or if it exited instead
+/// because its shutdown didn't finish in time, then it has to be started again
+fn restart_daemon(runtime_dir: &Path, log_path: &Path, codec: &FrameCodec) -> Option<Response> {
+    let socket_path = runtime_dir.join("dynisland.sock");
+    // the new daemon binds a new socket, that's how it's told apart from the old one
+    let old_socket = std::fs::metadata(&socket_path).ok()?.ino();
+    // not `connect`, the socket must not be deleted here
+    let stream = UnixStream::connect(&socket_path).ok()?;
+    match ipc::send_recv_message(stream, &Request::Restart, codec) {
+        Ok(Some(Response::Shutdown(report))) => log::info!("old daemon: {report}"),
+        Ok(Some(response)) if response.is_ok() => {}
+        Ok(Some(Response::Error {
+            kind: ErrorKind::Timeout,
+            message,
+        })) if wait_until(STOP_TIMEOUTS[0], || !pid_file::is_locked(runtime_dir)) => {
+            log::warn!("the daemon exited instead of restarting: {message}");
+            return None;
+        }
+        Ok(Some(response)) => return Some(response),
+        Ok(None) => {
+            return Some(Response::error(
+                ErrorKind::Internal,
+                "the daemon closed the connection without answering",
+            ))
+        }
+        Err(err)
+            if err.downcast_ref::<ProtocolMismatch>().is_some()
+                || err.downcast_ref::<NoHandshake>().is_some() =>
+        {
+            return None
+        }
+        Err(err) => return Some(client_error(err)),
+    }
+    let is_ready = || {
+        let is_new_socket =
+            std::fs::metadata(&socket_path).is_ok_and(|metadata| metadata.ino() != old_socket);
+        // the health check is answered once the layout manager is initialized
+        is_new_socket
+            && UnixStream::connect(&socket_path)
+                .ok()
+                .and_then(|stream| {
+                    ipc::send_recv_message(stream, &Request::HealthCheck, codec).ok()
+                })
+                .flatten()
+                .is_some_and(|response| response.is_ok())
+    };
+    if wait_until(RESTART_TIMEOUT, is_ready) {
+        return Some(Response::Ok);
+    }
+    Some(Response::error(
+        ErrorKind::StartupFailed,
+        format!(
+            "the daemon didn't come back in {}s after the restart, see {}",
+            RESTART_TIMEOUT.as_secs(),
+            log_path.display()
+        ),
+    ))
+}
+
+/// Polls `condition` until it's true or `timeout` is over
+fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while !condition() {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+enum Detached {
+    /// The process that ran the command, with the startup outcome of the daemon
+    Parent(Response),
+    /// The daemon, with the pipe used to report the startup outcome
+    Daemon(OwnedFd),
+}
+
+/// How long the detached parent waits for the daemon to report whether it started
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Reads the outcome the daemon writes in the pipe when it's ready or fails to start,
+/// the daemon keeps running if it's not ready after [`STARTUP_TIMEOUT`]
+fn read_startup_outcome(read_end: OwnedFd, log_file_path: &Path) -> Response {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+        match poll(
+            &mut [PollFd::new(read_end.as_fd(), PollFlags::POLLIN)],
+            timeout,
+        ) {
+            Ok(0) => {
+                return Response::error(
+                    ErrorKind::Timeout,
+                    format!(
+                        "the daemon wasn't ready after {}s, it may still be starting, see {}",
+                        STARTUP_TIMEOUT.as_secs(),
+                        log_file_path.display()
+                    ),
+                )
+            }
+            // readable or closed
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(err) => {
+                return Response::error(
+                    ErrorKind::Internal,
+                    format!("failed to wait for the startup outcome of the daemon: {err}"),
+                )
+            }
+        }
+    }
+    // the outcome is written with a single write, the rest of it follows right away
+    match FrameCodec::default().read_blocking(&mut File::from(read_end)) {
+        Ok(outcome) => outcome,
+        Err(FrameError::Closed) => Response::error(
+            ErrorKind::StartupFailed,
+            format!(
+                "the daemon exited before it was ready, see {}",
+                log_file_path.display()
+            ),
+        ),
+        Err(err) => Response::error(
+            ErrorKind::Internal,
+            format!("failed to read the startup outcome of the daemon: {err}"),
+        ),
+    }
+}
+
+fn detach(log_file: File, log_file_path: &Path) -> Result<Detached> {
+    // the processes spawned by the modules must not inherit the pipe, or the parent could miss a crash
+    let (read_end, write_end) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+
+    match unsafe { nix::unistd::fork()? } {
+        nix::unistd::ForkResult::Parent { child } => {
+            drop(write_end);
+            // the first child exits as soon as it has forked again
+            let _ = nix::sys::wait::waitpid(child, None);
+            return Ok(Detached::Parent(read_startup_outcome(
+                read_end,
+                log_file_path,
+            )));
+        }
+        nix::unistd::ForkResult::Child => {}
+    }
+    drop(read_end);
 
     // detach from terminal
-    let pid = match unsafe { nix::unistd::fork()? } {
-        nix::unistd::ForkResult::Child => nix::unistd::setsid(),
-        nix::unistd::ForkResult::Parent { .. } => {
-            // nix::unistd::daemon(false, false);
-            std::process::exit(0);
-        }
-    }?;
-
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(false)
-        .truncate(true)
-        .open(log_file_path)
-        .unwrap_or_else(|err| {
-            panic!(
-                "Error opening log file ({}), for writing: {err}",
-                log_file_path.to_string_lossy()
-            )
-        });
-    let fd = file.as_fd();
-
-    if nix::unistd::isatty(std::io::stdout().as_fd())? {
-        nix::unistd::dup2_stdout(fd)?;
-    }
-    if nix::unistd::isatty(std::io::stderr().as_fd())? {
-        nix::unistd::dup2_stderr(fd)?;
-    }
-    Ok(pid)
+    nix::unistd::setsid()?;
+    // fork again so that the daemon is not a session leader and can't get a controlling terminal back
+    if let nix::unistd::ForkResult::Parent { .. } = unsafe { nix::unistd::fork()? } {
+        std::process::exit(0);
+    }
+
+    let dev_null = File::open("/dev/null")?;
+
+    // nothing can keep the terminal (or a pipe of the caller) open
+    nix::unistd::dup2_stdin(dev_null.as_fd())?;
+    nix::unistd::dup2_stdout(log_file.as_fd())?;
+    nix::unistd::dup2_stderr(log_file.as_fd())?;
+    Ok(Detached::Daemon(write_end))
 }
@@ -1,10 +1,12 @@
-#[cfg(all(debug_assertions, not(feature = "embed_modules")))]
-use std::path::PathBuf;
-use std::{collections::HashMap, path::Path, rc::Rc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use abi_stable::{
     external_types::crossbeam_channel::RSender,
-    library::{lib_header_from_path, LibraryError},
+    library::{lib_header_from_path, LibHeader, LibraryError},
     std_types::{
         RBoxError, RResult,
         RResult::{RErr, ROk},
@@ -12,6 +14,7 @@ use abi_stable::{
     type_layout::TypeLayout,
     StableAbi,
 };
+use bincode::{Decode, Encode};
 use dynisland_core::abi::{
     abi_stable,
     layout::{LayoutManagerBuilderRef, LayoutManagerType},
@@ -19,13 +22,28 @@ use dynisland_core::abi::{
     module::{ModuleBuilderRef, ModuleType, UIServerCommand},
     SabiApplication,
 };
+use serde::Serialize;
 use tokio::sync::Mutex;
 
 use crate::{
     app::App,
+    events::Event,
     layout_manager::{self, fallback_layout},
 };
 
+/// Where a module was loaded from
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize)]
+#[serde(tag = "type", content = "path", rename_all = "snake_case")]
+pub enum ModuleOrigin {
+    /// Compiled into the binary with the `embed_modules` feature
+    Embedded,
+    /// Loaded from the shared library at this path
+    Library(String),
+}
+
+pub type ModuleConstructor =
+    extern "C" fn(RSender<UIServerCommand>) -> RResult<ModuleType, RBoxError>;
+
 impl App {
     pub(crate) fn load_modules(&mut self, config_dir: &Path) -> Vec<String> {
         let mut module_order = vec![];
@@ -33,30 +51,31 @@ impl App {
 
         if self.config.loaded_modules.contains(&"all".to_string()) {
             //load all modules available in order of hash (random order)
-            for module_def in module_def_map {
-                let module_name = module_def.0;
-                let module_constructor = module_def.1;
-
+            for (module_name, (module_constructor, origin)) in module_def_map {
                 let built_module = match module_constructor(self.app_send.clone().unwrap()) {
                     ROk(x) => x,
                     RErr(e) => {
                         log::error!("error during creation of {module_name}: {e:#?}");
+                        self.module_load_failed(&module_name, e.to_string());
                         continue;
                     }
                 };
 
                 module_order.push(module_name.to_string());
+                self.module_origins.insert(module_name.to_string(), origin);
                 self.module_map
                     .blocking_lock()
                     .insert(module_name.to_string(), built_module);
             }
         } else {
             //load only modules in the config in order of definition
-            for module_name in self.config.loaded_modules.iter() {
-                let module_constructor = module_def_map.get(module_name);
-                let module_constructor = match module_constructor {
+            let loaded_modules = self.config.loaded_modules.clone();
+            for module_name in loaded_modules.iter() {
+                let module_def = module_def_map.get(module_name);
+                let (module_constructor, origin) = match module_def {
                     None => {
                         log::warn!("module {} not found, skipping", module_name);
+                        self.module_load_failed(module_name, "module not found".to_string());
                         continue;
                     }
                     Some(x) => x,
@@ -66,10 +85,13 @@ impl App {
                     ROk(x) => x,
                     RErr(e) => {
                         log::error!("error during creation of {module_name}: {e:#?}");
+                        self.module_load_failed(module_name, e.to_string());
                         continue;
                     }
                 };
                 module_order.push(module_name.to_string());
+                self.module_origins
+                    .insert(module_name.to_string(), origin.clone());
                 // log::info!("loading module {}", module.get_name());
                 self.module_map
                     .blocking_lock()
@@ -81,6 +103,15 @@ impl App {
         module_order
     }
 
+    fn module_load_failed(&mut self, module_name: &str, error: String) {
+        let event = Event::ModuleLoadFailed {
+            module: module_name.to_string(),
+            error,
+        };
+        let _ = self.event_send.send(event.clone());
+        self.startup_events.push(event);
+    }
+
     pub(crate) fn load_layout_manager(&mut self, config_dir: &Path) {
         let layout_manager_definitions = crate::module_loading::get_lm_definitions(config_dir);
 
@@ -133,39 +164,42 @@ impl App {
 
 pub fn get_module_definitions(
     _config_dir: &Path,
-) -> HashMap<String, extern "C" fn(RSender<UIServerCommand>) -> RResult<ModuleType, RBoxError>> {
-    let mut module_def_map = HashMap::<
-        String,
-        extern "C" fn(RSender<UIServerCommand>) -> RResult<ModuleType, RBoxError>,
-    >::new();
+) -> HashMap<String, (ModuleConstructor, ModuleOrigin)> {
+    let mut module_def_map = HashMap::<String, (ModuleConstructor, ModuleOrigin)>::new();
 
-    let module_path = {
-        #[cfg(all(debug_assertions, not(feature = "embed_modules")))]
-        {
-            PathBuf::from("./target/debug/")
-        }
-        #[cfg(any(not(debug_assertions), feature = "embed_modules"))]
-        {
-            _config_dir.join("modules")
-        }
-    };
+    let module_path = module_dir(_config_dir);
 
     #[cfg(feature = "embed_modules")]
     {
         let clock_module = clock_module::instantiate_root_module();
-        module_def_map.insert(clock_module.name().into(), clock_module.new());
+        module_def_map.insert(
+            clock_module.name().into(),
+            (clock_module.new(), ModuleOrigin::Embedded),
+        );
 
         let music_module = music_module::instantiate_root_module();
-        module_def_map.insert(music_module.name().into(), music_module.new());
+        module_def_map.insert(
+            music_module.name().into(),
+            (music_module.new(), ModuleOrigin::Embedded),
+        );
 
         let script_module = script_module::instantiate_root_module();
-        module_def_map.insert(script_module.name().into(), script_module.new());
+        module_def_map.insert(
+            script_module.name().into(),
+            (script_module.new(), ModuleOrigin::Embedded),
+        );
 
         let systray_module = systray_module::instantiate_root_module();
-        module_def_map.insert(systray_module.name().into(), systray_module.new());
+        module_def_map.insert(
+            systray_module.name().into(),
+            (systray_module.new(), ModuleOrigin::Embedded),
+        );
 
         let power_module = power_module::instantiate_root_module();
-        module_def_map.insert(power_module.name().into(), power_module.new());
+        module_def_map.insert(
+            power_module.name().into(),
+            (power_module.new(), ModuleOrigin::Embedded),
+        );
     }
 
     let files = match std::fs::read_dir(&module_path) {
@@ -197,17 +231,11 @@ pub fn get_module_definitions(
         }
         log::debug!("loading module file: {:#?}", path);
 
-        let res = (|| {
-            let header = lib_header_from_path(&path)?;
-            // header.init_root_module::<ModuleBuilderRef>()
-            let layout1 = ModuleBuilderRef::LAYOUT;
-            let layout2 = header.layout().unwrap();
-            ensure_compatibility(layout1, layout2).and_then(|_| unsafe {
-                header
-                    .unchecked_layout::<ModuleBuilderRef>()
-                    .map_err(|err| err.into_library_error::<ModuleBuilderRef>())
-            })
-        })();
+        let res = compatible_header(&path, ModuleBuilderRef::LAYOUT).and_then(|header| unsafe {
+            header
+                .unchecked_layout::<ModuleBuilderRef>()
+                .map_err(|err| err.into_library_error::<ModuleBuilderRef>())
+        });
 
         let module_builder = match res {
             Ok(x) => x,
@@ -221,8 +249,9 @@ pub fn get_module_definitions(
         };
         let name = module_builder.name();
         let constructor = module_builder.new();
+        let origin = ModuleOrigin::Library(path.to_string_lossy().to_string());
 
-        module_def_map.insert(name.into(), constructor);
+        module_def_map.insert(name.into(), (constructor, origin));
     }
     module_def_map
 }
@@ -238,16 +267,7 @@ pub fn get_lm_definitions(
         extern "C" fn(SabiApplication) -> RResult<LayoutManagerType, RBoxError>,
     >::new();
 
-    let lm_path = {
-        #[cfg(all(debug_assertions, not(feature = "embed_modules")))]
-        {
-            PathBuf::from("./target/debug/")
-        }
-        #[cfg(any(not(debug_assertions), feature = "embed_modules"))]
-        {
-            _config_dir.join("layouts")
-        }
-    };
+    let lm_path = layout_dir(_config_dir);
 
     #[cfg(feature = "embed_modules")]
     {
@@ -284,17 +304,12 @@ pub fn get_lm_definitions(
         }
         log::debug!("loading layout manager file: {:#?}", path);
 
-        let res = (|| {
-            let header = lib_header_from_path(&path)?;
-            // header.init_root_module::<ModuleBuilderRef>()
-            let layout1 = LayoutManagerBuilderRef::LAYOUT;
-            let layout2 = header.layout().unwrap();
-            ensure_compatibility(layout1, layout2).and_then(|_| unsafe {
+        let res =
+            compatible_header(&path, LayoutManagerBuilderRef::LAYOUT).and_then(|header| unsafe {
                 header
                     .unchecked_layout::<LayoutManagerBuilderRef>()
                     .map_err(|err| err.into_library_error::<LayoutManagerBuilderRef>())
-            })
-        })();
+            });
 
         let lm_builder = match res {
             Ok(x) => x,
@@ -314,6 +329,48 @@ pub fn get_lm_definitions(
     lm_def_map
 }
 
+/// Directory the modules are loaded from
+pub fn module_dir(_config_dir: &Path) -> PathBuf {
+    #[cfg(all(debug_assertions, not(feature = "embed_modules")))]
+    {
+        PathBuf::from("./target/debug/")
+    }
+    #[cfg(any(not(debug_assertions), feature = "embed_modules"))]
+    {
+        _config_dir.join("modules")
+    }
+}
+
+/// Directory the layout managers are loaded from
+pub fn layout_dir(_config_dir: &Path) -> PathBuf {
+    #[cfg(all(debug_assertions, not(feature = "embed_modules")))]
+    {
+        PathBuf::from("./target/debug/")
+    }
+    #[cfg(any(not(debug_assertions), feature = "embed_modules"))]
+    {
+        _config_dir.join("layouts")
+    }
+}
+
+/// Loads the library at `path` and checks that its root module is compatible with `interface`,
+/// without instantiating it
+pub fn compatible_header(
+    path: &Path,
+    interface: &'static TypeLayout,
+) -> Result<&'static LibHeader, LibraryError> {
+    let header = lib_header_from_path(path)?;
+    // exported with `#[unsafe_no_layout_constant]`, its ABI can't be checked
+    let implementation = header.layout().ok_or_else(|| {
+        LibraryError::AbiInstability(RBoxError::from_fmt(&format_args!(
+            "{} doesn't export the layout of its root module",
+            path.display()
+        )))
+    })?;
+    ensure_compatibility(interface, implementation)?;
+    Ok(header)
+}
+
 pub fn ensure_compatibility(
     interface: &'static TypeLayout,
     implementation: &'static TypeLayout,
@@ -0,0 +1,147 @@
+//! PID file in the runtime directory, the daemon holds an exclusive `flock` on it while it's running.
+//!
+//! The lock is released by the kernel when the daemon dies, so a leftover file (or socket)
+//! doesn't mean that the daemon is still running, only the lock does.
+
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    unistd::Pid,
+};
+
+pub const PID_FILE_NAME: &str = "dynisland.pid";
+const LOCK_RETRIES: u32 = 5;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Another daemon holds the lock on the PID file
+#[derive(Debug)]
+pub struct AlreadyRunning {
+    pub pid: Option<Pid>,
+}
+
+impl Display for AlreadyRunning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "dynisland is already running (pid {pid})"),
+            None => write!(f, "dynisland is already running"),
+        }
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+pub struct PidFile {
+    path: PathBuf,
+    // the lock is released when this is dropped
+    _file: Flock<File>,
+}
+
+impl PidFile {
+    /// Locks the PID file in `runtime_dir` and writes the pid of this process in it,
+    /// fails with [`AlreadyRunning`] if another daemon holds the lock
+    pub fn acquire(runtime_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(runtime_dir)?;
+        let path = runtime_dir.join(PID_FILE_NAME);
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o644)
+                .open(&path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            let mut file = match lock_exclusive(file) {
+                Ok(file) => file,
+                Err((_, Errno::EWOULDBLOCK)) => {
+                    return Err(AlreadyRunning {
+                        pid: read_pid(runtime_dir),
+                    }
+                    .into())
+                }
+                Err((_, errno)) => {
+                    return Err(errno).with_context(|| format!("failed to lock {}", path.display()))
+                }
+            };
+            // the previous daemon removes the file before releasing the lock,
+            // if it was removed while this was waiting the lock is on a file that nobody else can see
+            if !is_same_file(&file, &path) {
+                continue;
+            }
+            file.set_len(0)?;
+            writeln!(file, "{}", std::process::id())?;
+            return Ok(Self { path, _file: file });
+        }
+    }
+
+    /// Removes the file before the lock is released
+    pub fn remove(self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Whether a daemon holds the lock on the PID file in `runtime_dir`,
+/// it's probed with the same lock that [`PidFile::acquire`] takes
+pub fn is_locked(runtime_dir: &Path) -> bool {
+    let Ok(file) = File::open(runtime_dir.join(PID_FILE_NAME)) else {
+        return false;
+    };
+    matches!(
+        Flock::lock(file, FlockArg::LockExclusiveNonblock),
+        Err((_, Errno::EWOULDBLOCK))
+    )
+}
+
+/// Takes the exclusive lock, retrying for a while because [`is_locked`] and [`remove_stale`]
+/// hold it for a moment while they probe a PID file
+fn lock_exclusive(mut file: File) -> Result<Flock<File>, (File, Errno)> {
+    for _ in 0..LOCK_RETRIES {
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Err((unlocked, Errno::EWOULDBLOCK)) => {
+                file = unlocked;
+                std::thread::sleep(LOCK_RETRY_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+    Flock::lock(file, FlockArg::LockExclusiveNonblock)
+}
+
+/// Pid written in the PID file, it can be stale, use [`is_locked`] to know if it's still running
+pub fn read_pid(runtime_dir: &Path) -> Option<Pid> {
+    let mut content = String::new();
+    File::open(runtime_dir.join(PID_FILE_NAME))
+        .ok()?
+        .read_to_string(&mut content)
+        .ok()?;
+    content.trim().parse().ok().map(Pid::from_raw)
+}
+
+/// Removes the PID file if no daemon holds the lock, returns whether it was removed
+pub fn remove_stale(runtime_dir: &Path) -> bool {
+    let path = runtime_dir.join(PID_FILE_NAME);
+    let Ok(file) = File::open(&path) else {
+        return false;
+    };
+    match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(file) if is_same_file(&file, &path) => std::fs::remove_file(&path).is_ok(),
+        _ => false,
+    }
+}
+
+fn is_same_file(file: &File, path: &Path) -> bool {
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(file), Ok(path)) => file.dev() == path.dev() && file.ino() == path.ino(),
+        _ => false,
+    }
+}
@@ -0,0 +1,107 @@
+//! Tracks the startup of the daemon, it's ready once the layout manager is initialized
+//! and the IPC socket is bound.
+//!
+//! When the daemon forks, the parent waits on a pipe until the daemon is ready or fails,
+//! the outcome is sent as a single [`Response`] frame.
+
+use std::{
+    fs::File,
+    os::fd::OwnedFd,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use dynisland_core::abi::log;
+
+use crate::{
+    ipc::{
+        codec::FrameCodec,
+        protocol::{ErrorKind, Response},
+    },
+    systemd,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    LayoutInitialized,
+    SocketBound,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    layout_initialized: bool,
+    socket_bound: bool,
+    /// Set once the daemon is ready or has failed, only the first outcome is reported
+    outcome: Option<Response>,
+    /// Write end of the pipe the parent is waiting on
+    pipe: Option<File>,
+}
+
+/// Can be shared between the UI thread and the IPC thread, the outcome is reported only once
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    state: Arc<Mutex<State>>,
+}
+
+impl Readiness {
+    /// Also reports the outcome to the process that is waiting on the other end of `pipe`
+    pub fn with_pipe(pipe: OwnedFd) -> Self {
+        let state = State {
+            pipe: Some(File::from(pipe)),
+            ..Default::default()
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn mark(&self, stage: Stage) {
+        let mut state = self.lock();
+        match stage {
+            Stage::LayoutInitialized => state.layout_initialized = true,
+            Stage::SocketBound => state.socket_bound = true,
+        }
+        log::debug!("startup stage reached: {stage:?}");
+        if state.layout_initialized && state.socket_bound && state.outcome.is_none() {
+            log::info!("dynisland is ready");
+            systemd::ready();
+            report(&mut state, Response::Ok);
+        }
+    }
+
+    /// Reports a startup failure, it's ignored if the daemon was already ready
+    pub fn fail(&self, kind: ErrorKind, message: impl Into<String>) {
+        let mut state = self.lock();
+        if state.outcome.is_some() {
+            return;
+        }
+        let message = message.into();
+        log::error!("startup failed: {message}");
+        systemd::status(&format!("Startup failed: {message}"));
+        report(&mut state, Response::error(kind, message));
+    }
+
+    /// Whether the daemon was ready or has failed
+    pub fn is_reported(&self) -> bool {
+        self.lock().outcome.is_some()
+    }
+
+    /// [`Response::Ok`] if the daemon was ready, the error if it failed
+    pub fn outcome(&self) -> Option<Response> {
+        self.lock().outcome.clone()
+    }
+
+    /// This is also used from the panic hook, so a poisoned lock is not a problem
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn report(state: &mut State, outcome: Response) {
+    // closing the pipe lets the parent exit
+    if let Some(mut pipe) = state.pipe.take() {
+        if let Err(err) = FrameCodec::default().write_blocking(&mut pipe, &outcome) {
+            log::warn!("failed to report the startup outcome to the parent process: {err}");
+        }
+    }
+    state.outcome = Some(outcome);
+}
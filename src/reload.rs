@@ -0,0 +1,248 @@
+//! Config reloads that only apply what changed and keep the last known-good config.
+//!
+//! Only the modules with a different config get it and restart their producers,
+//! the layout manager and the activities are updated only if their part changed.
+//! If `dynisland.ron` doesn't parse nothing is changed, if a module or the layout manager rejects its config
+//! it keeps the previous one and if the stylesheet doesn't compile the previous one stays loaded.
+//! The files that were last applied without errors are kept in memory as they were read,
+//! `dynisland config rollback` writes them back, with the files imported by the stylesheet.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use dynisland_core::abi::log;
+
+use crate::{
+    app::App,
+    config::{self, Config},
+    events::Event,
+    ipc::protocol::{ErrorKind, ReloadStatus, Response},
+    systemd,
+};
+
+/// The files replaced by a rollback are kept with this suffix
+pub const REJECTED_SUFFIX: &str = ".rejected";
+
+/// A part of the config that failed to load, the previous one is still in use
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    /// `dynisland.ron`, `dynisland.scss`, `module <name>` or `layout <name>`
+    pub part: String,
+    pub error: String,
+}
+
+impl Rejected {
+    pub fn new(part: impl Into<String>, error: impl Into<String>) -> Self {
+        Self {
+            part: part.into(),
+            error: error.into(),
+        }
+    }
+}
+
+/// Content of the config files that were last applied without errors, as they were parsed
+#[derive(Debug, Clone, Default)]
+pub struct KnownGood {
+    /// `None` if it never happened or `dynisland.ron` didn't exist
+    pub config: Option<String>,
+    /// `dynisland.scss` and the files it imports, empty if it never compiled or didn't exist
+    pub stylesheet: Vec<(PathBuf, Vec<u8>)>,
+}
+
+/// The parts of the config that differ between two reloads
+#[derive(Debug, Clone, Default)]
+pub struct ConfigDiff {
+    pub general: bool,
+    pub layout: bool,
+    /// Loaded modules with a different config
+    pub modules: Vec<String>,
+    /// Options that changed but are only read at startup
+    pub needs_restart: Vec<&'static str>,
+}
+
+impl ConfigDiff {
+    pub fn new(previous: &Config, config: &Config, layout_name: &str, modules: &[String]) -> Self {
+        let mut needs_restart = Vec::new();
+        if previous.layout != config.layout {
+            needs_restart.push("layout");
+        }
+        if previous.loaded_modules != config.loaded_modules {
+            needs_restart.push("loaded_modules");
+        }
+        if previous.ipc != config.ipc {
+            needs_restart.push("ipc");
+        }
+        if previous.log != config.log {
+            needs_restart.push("log");
+        }
+        if previous.debug != config.debug {
+            needs_restart.push("debug");
+        }
+        Self {
+            general: previous.general_style_config != config.general_style_config,
+            layout: previous.layout_configs.get(layout_name)
+                != config.layout_configs.get(layout_name),
+            modules: modules
+                .iter()
+                .filter(|name| previous.module_config.get(*name) != config.module_config.get(*name))
+                .cloned()
+                .collect(),
+            needs_restart,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.general && !self.layout && self.modules.is_empty() && self.needs_restart.is_empty()
+    }
+}
+
+impl Display for ConfigDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut changed = Vec::new();
+        if self.general {
+            changed.push("general_style_config".to_string());
+        }
+        if self.layout {
+            changed.push("layout config".to_string());
+        }
+        changed.extend(self.modules.iter().map(|name| format!("module {name}")));
+        changed.extend(self.needs_restart.iter().map(|option| option.to_string()));
+        write!(f, "{}", changed.join(", "))
+    }
+}
+
+impl App {
+    /// Reloads the parts of the config that changed, the modules with the same config are not touched
+    /// and a change of the stylesheet only reloads it. The result is in the status
+    pub(crate) fn reload_config(&mut self, config_dir: &Path) {
+        let mut rejected = Vec::new();
+        let mut summary = Vec::new();
+        match self.load_main_config(config_dir) {
+            Ok(previous) => {
+                let layout_name = self.layout.clone().unwrap().blocking_lock().0.clone();
+                let diff =
+                    ConfigDiff::new(&previous, &self.config, &layout_name, &self.module_order);
+                if diff.general {
+                    self.update_general_configs();
+                }
+                if diff.layout {
+                    rejected.extend(self.load_layout_config(Some(&previous)).err());
+                }
+                let module_rejected = self.load_module_configs(&diff.modules, &previous);
+                // a module that kept its previous config doesn't need new producers
+                let mut module_map = self.module_map.blocking_lock();
+                for name in &diff.modules {
+                    let part = format!("module {name}");
+                    if module_rejected.iter().any(|rejected| rejected.part == part) {
+                        continue;
+                    }
+                    if let Some(module) = module_map.get_mut(name) {
+                        module.restart_producers();
+                    }
+                }
+                drop(module_map);
+                rejected.extend(module_rejected);
+                for option in &diff.needs_restart {
+                    log::warn!("{option} changed, restart dynisland to apply it");
+                }
+                if !diff.is_empty() {
+                    summary.push(diff.to_string());
+                }
+            }
+            Err(err) => rejected.push(err),
+        }
+        match self.load_css(config_dir) {
+            Ok(true) => summary.push("stylesheet".to_string()),
+            Ok(false) => {}
+            Err(err) => rejected.push(err),
+        }
+        self.watch_config_files(config_dir);
+        if summary.is_empty() {
+            log::info!("config reloaded, nothing changed");
+        } else {
+            log::info!("config reloaded, changed: {}", summary.join(", "));
+        }
+        self.remember_known_good(&rejected);
+        let errors: Vec<String> = rejected
+            .iter()
+            .map(|rejected| rejected.error.clone())
+            .collect();
+        self.show_config_errors(&errors);
+        self.last_reload = Some(ReloadStatus::now(
+            errors,
+            rejected.into_iter().map(|rejected| rejected.part).collect(),
+        ));
+
+        systemd::reloaded();
+        let _ = self.event_send.send(Event::ConfigReloaded);
+    }
+
+    /// Answer to a reload, an error if a part of the config was rejected
+    pub(crate) fn reload_response(&self) -> Response {
+        match &self.last_reload {
+            Some(status) if !status.errors.is_empty() => Response::error(
+                ErrorKind::ConfigRejected,
+                format!(
+                    "the previous config is still used for {}:\n{}",
+                    status.kept.join(", "),
+                    status.errors.join("\n")
+                ),
+            ),
+            _ => Response::Ok,
+        }
+    }
+
+    /// Saves the content of the files that were loaded without errors, as they were when they were parsed
+    pub(crate) fn remember_known_good(&mut self, rejected: &[Rejected]) {
+        let stylesheet_rejected = rejected
+            .iter()
+            .any(|rejected| rejected.part == "dynisland.scss");
+        if rejected.len() > usize::from(stylesheet_rejected) {
+            log::warn!(
+                "the config was not applied completely, the known-good config was not updated"
+            );
+        } else {
+            self.known_good.config = self.config_source.clone();
+        }
+        if !stylesheet_rejected {
+            self.known_good.stylesheet = self.stylesheet_sources.clone();
+        }
+    }
+
+    /// Writes the known-good files over the ones that changed, those are copied to `<name>.rejected` first.
+    /// Returns the restored files
+    pub(crate) fn rollback_config(&self, config_dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut known_good: Vec<(PathBuf, &[u8])> = Vec::new();
+        if let Some(config) = &self.known_good.config {
+            known_good.push((config_dir.join("dynisland.ron"), config.as_bytes()));
+        }
+        for (path, content) in &self.known_good.stylesheet {
+            known_good.push((path.clone(), content));
+        }
+        if known_good.is_empty() {
+            bail!("no config was applied without errors yet, there is nothing to roll back to");
+        }
+        let mut restored = Vec::new();
+        for (path, content) in known_good {
+            let current = std::fs::read(&path).ok();
+            if current.as_deref() == Some(content) {
+                continue;
+            }
+            if let Some(current) = current {
+                let rejected_path = PathBuf::from(format!("{}{REJECTED_SUFFIX}", path.display()));
+                std::fs::write(&rejected_path, current)
+                    .with_context(|| format!("failed to write {}", rejected_path.display()))?;
+            }
+            config::write_config_file(&path, content)?;
+            log::info!("restored the last known-good {}", path.display());
+            restored.push(path);
+        }
+        if restored.is_empty() {
+            bail!("the config files are already the last known-good ones");
+        }
+        Ok(restored)
+    }
+}
@@ -0,0 +1,314 @@
+//! State-preserving restart.
+//!
+//! On [`Request::Restart`](crate::ipc::protocol::Request::Restart) the daemon saves the state of its activities
+//! in the runtime directory, shuts down and execs itself again (so an updated binary is picked up).
+//! The new process restores the state when the modules register their activities again.
+//!
+//! A notification that is still showing is shown again for the time it had left, the activity gets
+//! the mode it had before the notification. The ABI has no pinned flag, a hidden activity is saved as not `visible`.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use dynisland_core::{
+    abi::{layout::LayoutManagerType, log, module::ActivityIdentifier},
+    graphics::activity_widget::boxed_activity_mode::ActivityMode,
+};
+use gtk::{prelude::*, Widget};
+use serde::{Deserialize, Serialize};
+
+use crate::{app::App, error_activity, instance::Instance};
+
+pub const STATE_FILE_NAME: &str = "restart-state.json";
+
+/// Activities that are not registered again within this time are forgotten
+pub const RESTORE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedActivity {
+    pub module: String,
+    pub activity: String,
+    /// `None` for the default window
+    pub window: Option<String>,
+    /// Mode before the notification, if one was showing
+    pub mode: u8,
+    pub visible: bool,
+    #[serde(default)]
+    pub notification: Option<SavedNotification>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedNotification {
+    pub mode: u8,
+    /// `None` if the layout manager picks the duration
+    pub remaining_ms: Option<u64>,
+}
+
+impl SavedActivity {
+    fn is(&self, id: &ActivityIdentifier) -> bool {
+        self.module == id.module() && self.activity == id.activity()
+    }
+}
+
+/// The activities are in the order of the layout manager, so the order in every window is preserved
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedState {
+    /// Version of the daemon that saved the state
+    pub version: String,
+    pub activities: Vec<SavedActivity>,
+}
+
+impl SavedState {
+    /// Reads the state and removes the file, so it's only restored once
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let _ = std::fs::remove_file(path);
+        serde_json::from_str(&content)
+            .with_context(|| format!("invalid state in {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // written to a temporary file first, the new process must not see half a file
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Notification shown by the layout manager, it's saved with the state if it's still showing
+pub struct Notification {
+    mode: ActivityMode,
+    previous_mode: ActivityMode,
+    /// `None` if the layout manager picks the duration
+    ends_at: Option<Instant>,
+}
+
+/// Notifications requested since the activities were registered, by activity
+#[derive(Clone, Default)]
+pub struct Notifications(Rc<RefCell<HashMap<ActivityIdentifier, Notification>>>);
+
+impl Notifications {
+    /// Remembers a notification of `widget`, call it before the layout manager changes the mode
+    pub fn requested(
+        &self,
+        id: &ActivityIdentifier,
+        widget: &Widget,
+        mode: ActivityMode,
+        duration: Option<u64>,
+    ) {
+        let mut notifications = self.0.borrow_mut();
+        let current_mode = widget.property::<ActivityMode>("mode");
+        // a notification over another one goes back to the mode before the first one
+        let previous_mode = match notifications.get(id) {
+            Some(active) if active.is_showing(current_mode) => active.previous_mode,
+            _ => current_mode,
+        };
+        notifications.insert(
+            id.clone(),
+            Notification {
+                mode,
+                previous_mode,
+                ends_at: duration.map(|ms| Instant::now() + Duration::from_millis(ms)),
+            },
+        );
+    }
+
+    pub fn remove(&self, id: &ActivityIdentifier) {
+        self.0.borrow_mut().remove(id);
+    }
+
+    /// The notification of `id` that is still showing, with the mode before it
+    fn showing(
+        &self,
+        id: &ActivityIdentifier,
+        current_mode: ActivityMode,
+    ) -> Option<(SavedNotification, ActivityMode)> {
+        let notifications = self.0.borrow();
+        let notification = notifications.get(id)?;
+        if !notification.is_showing(current_mode) {
+            return None;
+        }
+        let saved = SavedNotification {
+            mode: notification.mode as u8,
+            remaining_ms: notification.ends_at.map(|ends_at| {
+                ends_at
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as u64
+            }),
+        };
+        Some((saved, notification.previous_mode))
+    }
+}
+
+impl Notification {
+    /// The mode is changed back when it ends, or by the user or the module before that
+    fn is_showing(&self, current_mode: ActivityMode) -> bool {
+        current_mode == self.mode && self.ends_at.is_none_or(|ends_at| ends_at > Instant::now())
+    }
+}
+
+/// State that is still being restored in the new process
+pub struct Restore {
+    state: SavedState,
+    /// Saved activities that were not registered yet
+    missing: Vec<SavedActivity>,
+}
+
+impl Restore {
+    pub fn new(state: SavedState) -> Self {
+        log::info!(
+            "restoring the state of {} activities saved by dynisland {}",
+            state.activities.len(),
+            state.version
+        );
+        Self {
+            missing: state.activities.clone(),
+            state,
+        }
+    }
+
+    /// Applies the saved mode and visibility to an activity before it's added to the layout,
+    /// returns the notification to show again once it's in the layout
+    pub fn restore_activity(
+        &mut self,
+        id: &ActivityIdentifier,
+        widget: &Widget,
+    ) -> Option<SavedNotification> {
+        let index = self.missing.iter().position(|saved| saved.is(id))?;
+        let saved = self.missing.remove(index);
+        match ActivityMode::try_from(saved.mode) {
+            Ok(mode) => widget.set_property("mode", mode),
+            Err(err) => log::warn!("invalid saved mode for {id}: {err}"),
+        }
+        widget.set_visible(saved.visible);
+        saved.notification
+    }
+
+    /// Once all the saved activities of the window of `id` are registered again,
+    /// they are moved back in the saved order
+    pub fn restore_order(&self, id: &ActivityIdentifier, layout: &mut LayoutManagerType) {
+        let window = id.metadata().window_name();
+        if !self.state.activities.iter().any(|saved| saved.is(id))
+            || self.missing.iter().any(|saved| saved.window == window)
+        {
+            return;
+        }
+        let registered = layout.list_activities();
+        let saved_order: Vec<&ActivityIdentifier> = self
+            .state
+            .activities
+            .iter()
+            .filter(|saved| saved.window == window)
+            .filter_map(|saved| registered.iter().find(|id| saved.is(id)))
+            .collect();
+        let current_order: Vec<&ActivityIdentifier> = registered
+            .iter()
+            .filter(|id| saved_order.contains(id))
+            .collect();
+        if current_order == saved_order {
+            return;
+        }
+        log::debug!("restoring the order of the activities in window {window:?}");
+        for id in saved_order {
+            let Some(widget) = layout.get_activity(id).into_option() else {
+                continue;
+            };
+            layout.remove_activity(id);
+            layout.add_activity(id, widget);
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Activities that were saved but were not registered again
+    pub fn missing(&self) -> impl Iterator<Item = String> + '_ {
+        self.missing
+            .iter()
+            .map(|saved| format!("{}@{}", saved.activity, saved.module))
+    }
+}
+
+impl App {
+    pub(crate) async fn save_state(&self) -> SavedState {
+        let mut activities = Vec::new();
+        if let Some(layout) = self.layout.clone() {
+            let layout = layout.lock().await;
+            for id in layout.1.list_activities() {
+                // it's registered again if the config still has errors
+                if id.module() == error_activity::MODULE_NAME {
+                    continue;
+                }
+                let Some(widget) = layout.1.get_activity(&id).into_option() else {
+                    continue;
+                };
+                let widget: Widget = match widget.try_into() {
+                    Ok(widget) => widget,
+                    Err(err) => {
+                        log::warn!("failed to save the state of {id}: {err:?}");
+                        continue;
+                    }
+                };
+                let mode = widget.property::<ActivityMode>("mode");
+                let (notification, mode) = match self.notifications.showing(&id, mode) {
+                    Some((notification, previous_mode)) => (Some(notification), previous_mode),
+                    None => (None, mode),
+                };
+                activities.push(SavedActivity {
+                    module: id.module().to_string(),
+                    activity: id.activity().to_string(),
+                    window: id.metadata().window_name(),
+                    mode: mode as u8,
+                    visible: widget.is_visible(),
+                    notification,
+                });
+            }
+        }
+        SavedState {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            activities,
+        }
+    }
+}
+
+/// Replaces this process with a new daemon that restores the state in `state_path`,
+/// it only returns if the exec failed
+pub fn exec(config_dir: &Path, instance: &Instance, state_path: &Path) -> anyhow::Error {
+    let executable = match current_executable() {
+        Ok(executable) => executable,
+        Err(err) => return err,
+    };
+    log::info!("restarting {}", executable.display());
+    let mut command = std::process::Command::new(&executable);
+    command.arg("--config-path").arg(config_dir);
+    if let Some(name) = instance.name() {
+        command.arg("--instance").arg(name);
+    }
+    command
+        .args(["daemon", "--no-daemonize", "--restore-state"])
+        .arg(state_path);
+    anyhow!(command.exec()).context(format!("failed to exec {}", executable.display()))
+}
+
+/// Path of the executable, if it was replaced (e.g. by an update) this is the path of the new one
+fn current_executable() -> Result<PathBuf> {
+    let executable =
+        std::env::current_exe().with_context(|| "failed to get the executable path")?;
+    let path = executable.to_string_lossy();
+    match path.strip_suffix(" (deleted)") {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(executable),
+    }
+}
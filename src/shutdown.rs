@@ -0,0 +1,164 @@
+//! Ordered shutdown of the daemon.
+//!
+//! The layout manager and the modules are shut down in reverse init order. What they see is:
+//! - the layout manager gets `cli_command("dynisland-shutdown <ms>")` (see [`SHUTDOWN_COMMAND`]),
+//!   then `remove_activity` for every activity it lists
+//! - every module gets the same `cli_command`, then it's dropped (which also stops its producer runtime)
+//!
+//! `<ms>` is the time left before [`SHUTDOWN_DEADLINE`], the ABI has no shutdown method so the hook
+//! goes through `cli_command`, a module that doesn't know the command returns an error and nothing else happens.
+//! A watchdog thread makes sure that a step that hangs can't keep the daemon alive after the deadline:
+//! the process exits with 1 and the modules that weren't reached yet are never dropped.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use dynisland_core::abi::{
+    abi_stable::std_types::{
+        RBoxError,
+        RResult::{self, RErr, ROk},
+        RString,
+    },
+    log,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    app::App,
+    events::Event,
+    ipc::protocol::{ErrorKind, RequestId, Response, ShutdownReport, ShutdownStep},
+};
+
+/// How long the whole shutdown can take before the daemon exits anyway
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(3);
+
+/// Passed to `cli_command` of the layout manager and of the modules before they are stopped,
+/// followed by the milliseconds left before [`SHUTDOWN_DEADLINE`]
+pub const SHUTDOWN_COMMAND: &str = "dynisland-shutdown";
+
+impl App {
+    /// Shuts down the layout manager and the modules, `id` gets the report,
+    /// or an error if the watchdog has to end the process
+    pub(crate) async fn shutdown(
+        &mut self,
+        id: RequestId,
+        server_response_send: &UnboundedSender<(RequestId, Response)>,
+    ) -> ShutdownReport {
+        log::info!("shutting down");
+        let start = Instant::now();
+        let watchdog = Watchdog::start(id, server_response_send.clone());
+        let mut steps = Vec::new();
+
+        if let Some(layout) = self.layout.clone() {
+            watchdog.step("layout");
+            let step_start = Instant::now();
+            let mut layout = layout.lock().await;
+            shutdown_hook("layout", layout.1.cli_command(hook_command(start)));
+            for activity in layout.1.list_activities() {
+                layout.1.remove_activity(&activity);
+                let _ = self.event_send.send(Event::activity_removed(&activity));
+            }
+            steps.push(ShutdownStep::new("layout", step_start.elapsed()));
+        }
+
+        // modules that are not in the init order (there shouldn't be any) go last
+        let mut module_map = self.module_map.lock().await;
+        let mut order: Vec<String> = self.module_order.iter().rev().cloned().collect();
+        order.extend(
+            module_map
+                .keys()
+                .filter(|name| !self.module_order.contains(name))
+                .cloned(),
+        );
+        for name in order {
+            let Some(module) = module_map.remove(&name) else {
+                continue;
+            };
+            watchdog.step(&name);
+            let step_start = Instant::now();
+            shutdown_hook(&name, module.cli_command(hook_command(start)));
+            drop(module);
+            let duration = step_start.elapsed();
+            log::debug!("module {name} stopped in {}ms", duration.as_millis());
+            steps.push(ShutdownStep::new(&name, duration));
+        }
+        watchdog.finish();
+
+        let report = ShutdownReport {
+            deadline_ms: SHUTDOWN_DEADLINE.as_millis() as u64,
+            total_ms: start.elapsed().as_millis() as u64,
+            steps,
+        };
+        log::info!("{report}");
+        report
+    }
+}
+
+/// The shutdown hook with the time left since the shutdown started at `start`
+fn hook_command(start: Instant) -> RString {
+    let remaining = SHUTDOWN_DEADLINE.saturating_sub(start.elapsed());
+    RString::from(format!("{SHUTDOWN_COMMAND} {}", remaining.as_millis()))
+}
+
+/// An error only means that `name` doesn't handle the hook
+fn shutdown_hook(name: &str, result: RResult<RString, RBoxError>) {
+    match result {
+        ROk(_) => log::debug!("{name} handled the shutdown hook"),
+        RErr(err) => log::trace!("{name} didn't handle the shutdown hook: {err}"),
+    }
+}
+
+/// Ends the process if the shutdown is still running at the deadline,
+/// the request that started the shutdown gets an error with the step that was stuck
+struct Watchdog {
+    current_step: Arc<Mutex<String>>,
+    done_send: mpsc::Sender<()>,
+}
+
+impl Watchdog {
+    fn start(id: RequestId, server_response_send: UnboundedSender<(RequestId, Response)>) -> Self {
+        let current_step = Arc::new(Mutex::new(String::from("start")));
+        let (done_send, done_recv) = mpsc::channel::<()>();
+        let step = current_step.clone();
+        let spawned = thread::Builder::new()
+            .name("shutdown-watchdog".to_string())
+            .spawn(move || {
+                if let Err(mpsc::RecvTimeoutError::Timeout) =
+                    done_recv.recv_timeout(SHUTDOWN_DEADLINE)
+                {
+                    let step = step.lock().unwrap_or_else(|err| err.into_inner()).clone();
+                    let message = format!(
+                        "shutdown didn't finish in {}ms, it was stuck on {step}, exiting anyway",
+                        SHUTDOWN_DEADLINE.as_millis()
+                    );
+                    log::error!("{message}");
+                    let _ = server_response_send
+                        .send((id, Response::error(ErrorKind::Timeout, message)));
+                    // give the ipc thread some time to send the response
+                    thread::sleep(Duration::from_millis(200));
+                    std::process::exit(1);
+                }
+            });
+        if let Err(err) = spawned {
+            log::warn!("failed to start the shutdown watchdog: {err}");
+        }
+        Self {
+            current_step,
+            done_send,
+        }
+    }
+
+    fn step(&self, name: &str) {
+        *self
+            .current_step
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = name.to_string();
+    }
+
+    fn finish(self) {
+        let _ = self.done_send.send(());
+    }
+}
@@ -0,0 +1,85 @@
+//! Health details of the running daemon, used by `dynisland status`.
+
+use std::collections::HashMap;
+
+use dynisland_core::abi::gdk;
+use gtk::prelude::*;
+
+use crate::{
+    app::App,
+    ipc::protocol::{DaemonStatus, LayoutStatus, ModuleStatus, WindowInfo},
+    module_loading::ModuleOrigin,
+};
+
+impl App {
+    pub(crate) async fn status(&self) -> DaemonStatus {
+        let mut activities: HashMap<String, u32> = HashMap::new();
+        let layout = match self.layout.clone() {
+            Some(layout) => {
+                let layout = layout.lock().await;
+                for activity in layout.1.list_activities() {
+                    *activities.entry(activity.module().to_string()).or_default() += 1;
+                }
+                let windows = layout
+                    .1
+                    .list_windows()
+                    .into_iter()
+                    .map(|name| WindowInfo {
+                        monitor: self.window_monitor(&name),
+                        name: name.into_string(),
+                    })
+                    .collect();
+                Some(LayoutStatus {
+                    name: layout.0.clone(),
+                    windows,
+                })
+            }
+            None => None,
+        };
+
+        let mut modules: Vec<ModuleStatus> = self
+            .module_map
+            .lock()
+            .await
+            .keys()
+            .map(|name| ModuleStatus {
+                name: name.clone(),
+                origin: self
+                    .module_origins
+                    .get(name)
+                    .cloned()
+                    .unwrap_or(ModuleOrigin::Embedded),
+                activities: activities.get(name).copied().unwrap_or(0),
+            })
+            .collect();
+        modules.sort_by(|a, b| a.name.cmp(&b.name));
+
+        DaemonStatus {
+            pid: std::process::id(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            config_dir: self.config_dir.display().to_string(),
+            renderer: self.renderer.clone(),
+            layout,
+            modules,
+            last_reload: self.last_reload.clone(),
+        }
+    }
+
+    /// The ABI can't tell which gtk window is a window of the layout manager, the title is matched
+    /// with the window name (the fallback layout sets it), `None` unless exactly one window matches
+    fn window_monitor(&self, window_name: &str) -> Option<String> {
+        let mut windows = self
+            .application
+            .windows()
+            .into_iter()
+            .filter(|window| window.title().is_some_and(|title| title == window_name));
+        let window = windows.next()?;
+        if windows.next().is_some() {
+            return None;
+        }
+        let surface = window.surface()?;
+        let monitor = gdk::Display::default()?.monitor_at_surface(&surface)?;
+        monitor.connector().map(|connector| connector.to_string())
+    }
+}
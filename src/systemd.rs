@@ -0,0 +1,270 @@
+//! Support for running the daemon as a systemd user service with `Type=notify`.
+//!
+//! The notifications are sent to `$NOTIFY_SOCKET` (see `sd_notify(3)`), they are ignored if it's not set,
+//! so nothing changes when dynisland is started outside of systemd.
+
+use std::{
+    ffi::OsStr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use dynisland_core::abi::{glib, log};
+
+use crate::instance::Instance;
+
+static NOTIFY_SOCKET: LazyLock<Option<SocketAddr>> =
+    LazyLock::new(|| parse_notify_socket(&std::env::var_os("NOTIFY_SOCKET")?));
+
+/// A path, or an abstract socket if it starts with `@`
+fn parse_notify_socket(path: &OsStr) -> Option<SocketAddr> {
+    let path = path.as_encoded_bytes();
+    let addr = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(Path::new(std::str::from_utf8(path).ok()?)),
+    };
+    addr.inspect_err(|err| log::warn!("invalid NOTIFY_SOCKET: {err}"))
+        .ok()
+}
+
+/// Whether the daemon was started by a service manager that expects notifications
+pub fn is_supervised() -> bool {
+    NOTIFY_SOCKET.is_some()
+}
+
+/// Sends `state` (newline separated `KEY=VALUE` assignments) to the service manager,
+/// returns `false` if there is no service manager to notify
+pub fn notify(state: &str) -> bool {
+    match NOTIFY_SOCKET.as_ref() {
+        Some(addr) => notify_to(addr, state),
+        None => false,
+    }
+}
+
+fn notify_to(addr: &SocketAddr, state: &str) -> bool {
+    let result =
+        UnixDatagram::unbound().and_then(|socket| socket.send_to_addr(state.as_bytes(), addr));
+    match result {
+        Ok(_) => true,
+        Err(err) => {
+            log::warn!("failed to notify the service manager ({state:?}): {err}");
+            false
+        }
+    }
+}
+
+pub fn ready() {
+    if notify("READY=1\nSTATUS=Running") {
+        log::debug!("notified the service manager that dynisland is ready");
+    }
+}
+
+pub fn status(status: &str) {
+    // the status is a single line
+    notify(&format!("STATUS={}", status.replace('\n', " ")));
+}
+
+pub fn reloading() {
+    notify("RELOADING=1\nSTATUS=Reloading config");
+}
+
+pub fn reloaded() {
+    notify("READY=1\nSTATUS=Running");
+}
+
+pub fn stopping() {
+    notify("STOPPING=1\nSTATUS=Stopping");
+}
+
+/// How often `WATCHDOG=1` has to be sent, it's half of `WatchdogSec=` so that a late ping isn't fatal
+pub fn watchdog_interval() -> Option<Duration> {
+    if !is_supervised() {
+        return None;
+    }
+    parse_watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+    )
+}
+
+/// From `WATCHDOG_USEC` and `WATCHDOG_PID`, the watchdog is meant for another process if the pid is not ours
+fn parse_watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = usec?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Pings the watchdog from the main loop, if the UI thread hangs systemd restarts the service
+pub fn start_watchdog() {
+    let (Some(addr), Some(interval)) = (NOTIFY_SOCKET.as_ref(), watchdog_interval()) else {
+        return;
+    };
+    log::info!("sending watchdog pings every {}ms", interval.as_millis());
+    ping_watchdog(addr.clone(), interval);
+}
+
+fn ping_watchdog(addr: SocketAddr, interval: Duration) {
+    glib::timeout_add_local(interval, move || {
+        notify_to(&addr, "WATCHDOG=1");
+        glib::ControlFlow::Continue
+    });
+}
+
+/// Name of the unit file, named instances get their own unit
+pub fn unit_name(instance: &Instance) -> String {
+    match instance.name() {
+        Some(name) => format!("dynisland-{name}.service"),
+        None => "dynisland.service".to_string(),
+    }
+}
+
+pub fn default_unit_path(instance: &Instance) -> PathBuf {
+    glib::user_config_dir()
+        .join("systemd/user")
+        .join(unit_name(instance))
+}
+
+/// Generates a user unit that starts `executable` in the foreground and waits for it to be ready
+pub fn unit(executable: &Path, config_dir: Option<&Path>, instance: &Instance) -> String {
+    let mut args = vec![quote(&executable.to_string_lossy())];
+    if let Some(config_dir) = config_dir {
+        args.push("--config-path".to_string());
+        args.push(quote(&config_dir.to_string_lossy()));
+    }
+    if let Some(name) = instance.name() {
+        args.push("--instance".to_string());
+        args.push(quote(name));
+    }
+    let command = args.join(" ");
+    let description = match instance.name() {
+        Some(name) => format!("Dynisland ({name})"),
+        None => "Dynisland".to_string(),
+    };
+    format!(
+        "[Unit]
+Description={description}
+Documentation=https://github.com/cr3eperall/dynisland
+PartOf=graphical-session.target
+After=graphical-session.target
+Requisite=graphical-session.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={command} daemon --no-daemonize
+ExecReload={command} reload
+Restart=on-failure
+WatchdogSec=30
+
+[Install]
+WantedBy=graphical-session.target
+"
+    )
+}
+
+/// Writes the unit to `path`, an existing file is only replaced if `force` is set
+pub fn install_service(unit: &str, path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!(
+            "{} already exists, use --force to replace it",
+            path.display()
+        );
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    std::fs::write(path, unit).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Quotes an argument of `ExecStart=`, see "Command lines" in `systemd.service(5)`
+fn quote(arg: &str) -> String {
+    // specifiers and environment variables are expanded even inside quotes
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return arg;
+    }
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifications_and_watchdog_pings_are_sent_to_the_socket() {
+        let path = std::env::temp_dir().join(format!("dynisland-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let service_manager = UnixDatagram::bind(&path).unwrap();
+        service_manager
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = parse_notify_socket(path.as_os_str()).unwrap();
+        let mut buf = [0u8; 256];
+
+        assert!(notify_to(&addr, "READY=1\nSTATUS=Running"));
+        let len = service_manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Running");
+
+        // the pings are attached to the default context, like in the daemon
+        let context = glib::MainContext::default();
+        let _owner = context.acquire().unwrap();
+        ping_watchdog(addr, Duration::from_millis(100));
+        // waits for the first ping
+        context.iteration(true);
+        let len = service_manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn watchdog_interval_is_half_of_watchdog_usec() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            parse_watchdog_interval(Some("200000"), Some(&pid)),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            parse_watchdog_interval(Some("200000"), None),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(parse_watchdog_interval(Some("200000"), Some("1")), None);
+        assert_eq!(parse_watchdog_interval(Some("0"), None), None);
+        assert_eq!(parse_watchdog_interval(None, None), None);
+    }
+
+    #[test]
+    fn abstract_notify_socket() {
+        let addr = parse_notify_socket(OsStr::new("@dynisland/notify")).unwrap();
+        assert_eq!(addr.as_abstract_name(), Some(&b"dynisland/notify"[..]));
+    }
+}